@@ -0,0 +1,575 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use yew::platform::spawn_local;
+use yew::platform::time::sleep;
+
+use crate::codec::{self, Cbor};
+use crate::{DropdownMsg, ModalMsg, MyMsg};
+
+struct Subscriber {
+    /// None for all the messages
+    scopes: Option<HashSet<String>>,
+    callback: Rc<dyn Fn(MyMsg)>,
+}
+
+#[derive(Default)]
+struct Registry {
+    next_id: u64,
+    subscribers: BTreeMap<u64, Subscriber>,
+    /// Messages sent by a callback, delivered once the current one has reached every bridge
+    pending: VecDeque<MyMsg>,
+    delivering: bool,
+    next_request: u64,
+    /// Requests waiting for their reply
+    requests: HashMap<u64, Rc<RefCell<Slot>>>,
+}
+
+/// A question sent with `MyEventBus::request` to the bridges of its scope, see `MyMsg::scope`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum BusQuery {
+    /// Paths checked in the `FileTree` with this `tree_id`, answered with `BusReply::Paths`
+    FileTreeChecked(String),
+    /// Path selected in the `FileTree` with this `tree_id`, answered with `BusReply::Path`
+    FileTreeSelected(String),
+    /// Whether the `Modal` or `ModalCard` with this id is open, answered with `BusReply::Open`
+    ModalOpen(String),
+    /// Series shown by the `ChartView` with this id, answered with `BusReply::Series`
+    #[cfg(feature = "chart")]
+    ChartSeries(String),
+    /// Application requests, see `Channel::request`: the name of the channel and the request in CBOR
+    Channel(String, Vec<u8>),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum BusReply {
+    Paths(Vec<String>),
+    Path(Option<String>),
+    Open(bool),
+    #[cfg(feature = "chart")]
+    Series(Option<crate::chart::chartcard::ChartSeries>),
+    /// Reply to a `BusQuery::Channel`, in CBOR
+    Channel(Vec<u8>),
+}
+
+impl BusQuery {
+    pub fn scope(&self) -> String {
+        match self {
+            BusQuery::FileTreeChecked(id) | BusQuery::FileTreeSelected(id) => format!("FileTree/{}", id),
+            BusQuery::ModalOpen(id) => format!("Modal/{}", id),
+            #[cfg(feature = "chart")]
+            BusQuery::ChartSeries(id) => format!("Chart/{}", id),
+            BusQuery::Channel(name, _) => name.clone(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Slot {
+    /// Some once the request is over, with None if there was no reply in time
+    reply: Option<Option<BusReply>>,
+    waker: Option<Waker>,
+}
+
+/// The reply to a request, the request is forgotten when dropped.
+struct PendingReply {
+    id: u64,
+    slot: Rc<RefCell<Slot>>,
+}
+
+impl Future for PendingReply {
+    type Output = Option<BusReply>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = self.slot.borrow_mut();
+        match slot.reply.take() {
+            Some(reply) => Poll::Ready(reply),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for PendingReply {
+    fn drop(&mut self) {
+        let _ = BUS.try_with(|bus| bus.borrow_mut().requests.remove(&self.id));
+    }
+}
+
+/// Ends the request `id`, if it is still waiting.
+fn resolve(id: u64, reply: Option<BusReply>) {
+    let Some(slot) = BUS.with(|bus| bus.borrow_mut().requests.remove(&id)) else {
+        return;
+    };
+    let waker = {
+        let mut slot = slot.borrow_mut();
+        slot.reply = Some(reply);
+        slot.waker.take()
+    };
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+thread_local! {
+    static BUS: RefCell<Registry> = RefCell::new(Registry::default());
+}
+
+/// Messages between the components of the page. Bridges are kept in the thread of the page,
+/// so no worker script has to be shipped with the application. Messages are delivered in the
+/// order they are sent, to the bridges in the order they were created.
+///
+/// Struct components keep a `BusBridge` for as long as they listen, function components use
+/// `use_bus`, `use_bus_scoped` or `use_channel`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MyEventBus;
+
+impl MyEventBus {
+    pub fn dispatcher() -> BusDispatcher {
+        BusDispatcher
+    }
+
+    /// Send `msg` to the bridges of its scope.
+    pub fn send(msg: MyMsg) {
+        let msg = match msg {
+            MyMsg::Subscribe(_) => return,
+            MyMsg::Reply(id, reply) => return resolve(id, Some(reply)),
+            msg => msg,
+        };
+        let first = BUS.with(|bus| {
+            let mut bus = bus.borrow_mut();
+            if bus.delivering {
+                bus.pending.push_back(msg);
+                None
+            } else {
+                bus.delivering = true;
+                Some(msg)
+            }
+        });
+        let Some(mut msg) = first else {
+            return;
+        };
+        loop {
+            let scope = msg.scope();
+            let ids: Vec<u64> = BUS.with(|bus| {
+                bus.borrow()
+                    .subscribers
+                    .iter()
+                    .filter(|(_, s)| s.scopes.as_ref().map_or(true, |scopes| scopes.contains(&scope)))
+                    .map(|(id, _)| *id)
+                    .collect()
+            });
+            for id in ids {
+                // the bridge may have been dropped by a previous callback
+                let callback = BUS.with(|bus| bus.borrow().subscribers.get(&id).map(|s| s.callback.clone()));
+                if let Some(callback) = callback {
+                    callback(msg.clone());
+                }
+            }
+            let next = BUS.with(|bus| {
+                let mut bus = bus.borrow_mut();
+                let next = bus.pending.pop_front();
+                bus.delivering = next.is_some();
+                next
+            });
+            match next {
+                Some(next) => msg = next,
+                None => break,
+            }
+        }
+    }
+
+    /// Call `callback` with every message until the bridge is dropped.
+    pub fn bridge(callback: Rc<dyn Fn(MyMsg)>) -> BusBridge {
+        Self::subscribe(None, callback)
+    }
+
+    /// A bridge woken only by the messages of `scopes`, see `MyMsg::scope`.
+    pub fn bridge_scoped(scopes: Vec<String>, callback: Rc<dyn Fn(MyMsg)>) -> BusBridge {
+        Self::subscribe(Some(scopes.into_iter().collect()), callback)
+    }
+
+    /// Send `query` to the bridges of its scope and wait for the first reply, None if there is
+    /// none within `timeout`, e.g. when no component has the id of the query.
+    ///
+    /// ```ignore
+    /// let query = BusQuery::FileTreeChecked("points".to_string());
+    /// ctx.link().send_future(async move {
+    ///     match MyEventBus::request(query, Duration::from_secs(1)).await {
+    ///         Some(BusReply::Paths(paths)) => Msg::Export(paths),
+    ///         _ => Msg::None,
+    ///     }
+    /// });
+    /// ```
+    pub async fn request(query: BusQuery, timeout: Duration) -> Option<BusReply> {
+        let pending = Self::ask(query);
+        let id = pending.id;
+        spawn_local(async move {
+            sleep(timeout).await;
+            resolve(id, None);
+        });
+        pending.await
+    }
+
+    /// Answer the request `id`, a request only keeps its first reply.
+    pub fn reply(id: u64, reply: BusReply) {
+        Self::send(MyMsg::Reply(id, reply));
+    }
+
+    fn ask(query: BusQuery) -> PendingReply {
+        let (id, slot) = BUS.with(|bus| {
+            let mut bus = bus.borrow_mut();
+            bus.next_request += 1;
+            let id = bus.next_request;
+            let slot = Rc::new(RefCell::new(Slot::default()));
+            bus.requests.insert(id, slot.clone());
+            (id, slot)
+        });
+        // registered first, the reply may be given while the request is delivered
+        Self::send(MyMsg::Request(id, query));
+        PendingReply { id, slot }
+    }
+
+    fn subscribe(scopes: Option<HashSet<String>>, callback: Rc<dyn Fn(MyMsg)>) -> BusBridge {
+        BUS.with(|bus| {
+            let mut bus = bus.borrow_mut();
+            bus.next_id += 1;
+            let id = bus.next_id;
+            bus.subscribers.insert(id, Subscriber { scopes, callback });
+            BusBridge { id }
+        })
+    }
+}
+
+/// Sends messages to `MyEventBus`, for components that do not listen to it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BusDispatcher;
+
+impl BusDispatcher {
+    pub fn send(&self, msg: MyMsg) {
+        MyEventBus::send(msg);
+    }
+}
+
+/// Subscription to `MyEventBus`, removed when dropped.
+#[derive(Debug)]
+pub struct BusBridge {
+    id: u64,
+}
+
+impl BusBridge {
+    /// Send `msg` to the bus, `MyMsg::Subscribe` changes the scopes of this bridge instead.
+    pub fn send(&mut self, msg: MyMsg) {
+        match msg {
+            MyMsg::Subscribe(scopes) => BUS.with(|bus| {
+                if let Some(s) = bus.borrow_mut().subscribers.get_mut(&self.id) {
+                    s.scopes = Some(scopes.into_iter().collect());
+                }
+            }),
+            msg => MyEventBus::send(msg),
+        }
+    }
+}
+
+impl Drop for BusBridge {
+    fn drop(&mut self) {
+        // the registry may be gone if the thread is exiting
+        let _ = BUS.try_with(|bus| bus.borrow_mut().subscribers.remove(&self.id));
+    }
+}
+
+impl MyMsg {
+    /// Bridges subscribed with `MyEventBus::bridge_scoped` only receive the messages of their scopes:
+    /// "FileTree", "Modal", "Dropdown", "Chart" and "Loading" for the built-in messages,
+    /// followed by "/" and the id of the component for those sent to one of them,
+    /// e.g. "Modal/settings" for `ModalMsg::CloseFromAgent("settings")`.
+    /// Messages of a `Channel` have its name as scope.
+    pub fn scope(&self) -> String {
+        match self {
+            MyMsg::FileTree(_) => "FileTree".to_string(),
+            MyMsg::FileTreeWithId(id, _) => format!("FileTree/{}", id),
+            MyMsg::Modal(ModalMsg::CloseFromAgent(id) | ModalMsg::OpenFromAgent(id)) => format!("Modal/{}", id),
+            MyMsg::Modal(_) => "Modal".to_string(),
+            MyMsg::Dropdown(DropdownMsg::CloseFromAgent(id)) => format!("Dropdown/{}", id),
+            MyMsg::Dropdown(_) => "Dropdown".to_string(),
+            #[cfg(feature = "chart")]
+            MyMsg::ChartMsg(crate::chart::chartcard::Msg::ChangeTemplate(id, _))
+            | MyMsg::ChartMsg(crate::chart::chartcard::Msg::UpdateSeries(id, _)) => format!("Chart/{}", id),
+            #[cfg(feature = "chart")]
+            MyMsg::ChartMsg(_) => "Chart".to_string(),
+            MyMsg::Loading(_) => "Loading".to_string(),
+            MyMsg::Channel(name, _) => name.clone(),
+            MyMsg::Request(_, query) => query.scope(),
+            MyMsg::Subscribe(_) | MyMsg::Reply(..) => String::new(),
+        }
+    }
+
+    /// The scopes of a component with `id` receiving the messages of `kind`, e.g. "Modal".
+    pub fn scopes_of(kind: &str, id: &str) -> Vec<String> {
+        vec![kind.to_string(), format!("{}/{}", kind, id)]
+    }
+}
+
+/// Application messages of type `T` carried by `MyEventBus` next to the built-in ones,
+/// as `MyMsg::Channel` with the name of the channel and the message encoded in CBOR.
+///
+/// ```ignore
+/// const ALARMS: Channel<Alarm> = Channel::new("alarms");
+///
+/// // in create
+/// let link = ctx.link().clone();
+/// let bridge = ALARMS.bridge(move |alarm| link.send_message(Msg::Alarm(alarm)));
+/// // anywhere
+/// ALARMS.send(&Alarm { point: 3, level: 2 });
+/// ```
+pub struct Channel<T> {
+    name: Cow<'static, str>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for Channel<T> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Channel<T> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name: Cow::Borrowed(name),
+            _marker: PhantomData,
+        }
+    }
+
+    /// A channel whose name is known at runtime, e.g. one per device.
+    pub fn named<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: Cow::Owned(name.into()),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + 'static> Channel<T> {
+    fn encode<V: Serialize>(&self, value: &V) -> Option<Vec<u8>> {
+        match codec::encode(&Cbor, value) {
+            Ok(payload) => Some(payload),
+            Err(e) => {
+                log::warn!("!!Failed to encode message of channel {}, {}", self.name, e);
+                None
+            }
+        }
+    }
+
+    fn decode<V: DeserializeOwned>(&self, payload: &[u8]) -> Option<V> {
+        match codec::decode(&Cbor, payload) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                log::warn!("!!Failed to decode message of channel {}, {}", self.name, e);
+                None
+            }
+        }
+    }
+
+    /// The message to give to `MyEventBus`, None if `value` can not be encoded.
+    pub fn message(&self, value: &T) -> Option<MyMsg> {
+        Some(MyMsg::Channel(self.name.to_string(), self.encode(value)?))
+    }
+
+    /// The value carried by `msg` if it was sent on this channel.
+    pub fn read(&self, msg: &MyMsg) -> Option<T> {
+        let MyMsg::Channel(name, payload) = msg else {
+            return None;
+        };
+        if *name != self.name {
+            return None;
+        }
+        self.decode(payload)
+    }
+
+    /// Send `value` to every bridge of `MyEventBus`.
+    pub fn send(&self, value: &T) {
+        if let Some(msg) = self.message(value) {
+            MyEventBus::send(msg);
+        }
+    }
+
+    /// Call `callback` with the messages of this channel until the bridge is dropped.
+    pub fn bridge<F: Fn(T) + 'static>(&self, callback: F) -> BusBridge {
+        let channel = self.clone();
+        MyEventBus::bridge_scoped(vec![self.name.to_string()], Rc::new(move |msg: MyMsg| {
+            if let Some(value) = channel.read(&msg) {
+                callback(value);
+            }
+        }))
+    }
+
+    /// Ask the bridge answering on this channel with `respond`, see `MyEventBus::request`.
+    pub async fn request<R: DeserializeOwned>(&self, value: &T, timeout: Duration) -> Option<R> {
+        let query = BusQuery::Channel(self.name.to_string(), self.encode(value)?);
+        match MyEventBus::request(query, timeout).await? {
+            BusReply::Channel(payload) => self.decode(&payload),
+            _ => None,
+        }
+    }
+
+    /// Answer the requests of this channel with `handler` until the bridge is dropped,
+    /// nothing is replied when it returns None.
+    pub fn respond<R, F>(&self, handler: F) -> BusBridge
+    where
+        R: Serialize,
+        F: Fn(T) -> Option<R> + 'static,
+    {
+        let channel = self.clone();
+        MyEventBus::bridge_scoped(vec![self.name.to_string()], Rc::new(move |msg: MyMsg| {
+            let MyMsg::Request(id, BusQuery::Channel(name, payload)) = msg else {
+                return;
+            };
+            if name != channel.name {
+                return;
+            }
+            let reply = channel.decode(&payload).and_then(&handler).and_then(|r| channel.encode(&r));
+            if let Some(reply) = reply {
+                MyEventBus::reply(id, BusReply::Channel(reply));
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::components::myloading::LoadingMsg;
+
+    /// A bridge keeping the scopes of the messages it receives.
+    fn recorder(scopes: Option<Vec<String>>) -> (BusBridge, Rc<RefCell<Vec<String>>>) {
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let log = received.clone();
+        let callback = Rc::new(move |msg: MyMsg| log.borrow_mut().push(msg.scope()));
+        let bridge = match scopes {
+            Some(scopes) => MyEventBus::bridge_scoped(scopes, callback),
+            None => MyEventBus::bridge(callback),
+        };
+        (bridge, received)
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Alarm {
+        point: u64,
+        level: u8,
+    }
+
+    const ALARMS: Channel<Alarm> = Channel::new("alarms");
+
+    #[test]
+    fn test_channel() {
+        let alarm = Alarm { point: 3, level: 2 };
+        let msg = ALARMS.message(&alarm).unwrap();
+        assert!(matches!(&msg, MyMsg::Channel(name, _) if name == "alarms"));
+        assert_eq!(ALARMS.read(&msg), Some(alarm));
+        // other channels and built-in messages are skipped
+        assert_eq!(Channel::<Alarm>::named("events").read(&msg), None);
+        assert_eq!(Channel::<String>::named("alarms").read(&msg), None);
+        assert_eq!(ALARMS.read(&MyMsg::Loading(LoadingMsg::Show)), None);
+    }
+
+    #[test]
+    fn test_scope() {
+        assert_eq!(MyMsg::Loading(LoadingMsg::Hide).scope(), "Loading");
+        assert_eq!(MyMsg::Modal(ModalMsg::Open).scope(), "Modal");
+        let close = MyMsg::Modal(ModalMsg::CloseFromAgent("settings".to_string()));
+        assert!(MyMsg::scopes_of("Modal", "settings").contains(&close.scope()));
+        assert!(!MyMsg::scopes_of("Modal", "about").contains(&close.scope()));
+        assert!(!MyMsg::scopes_of("Dropdown", "settings").contains(&close.scope()));
+        assert_eq!(ALARMS.message(&Alarm { point: 1, level: 1 }).unwrap().scope(), "alarms");
+        let query = MyMsg::Request(1, BusQuery::ModalOpen("settings".to_string()));
+        assert!(MyMsg::scopes_of("Modal", "settings").contains(&query.scope()));
+        assert!(!MyMsg::scopes_of("Modal", "about").contains(&query.scope()));
+    }
+
+    #[test]
+    fn test_bus() {
+        let (_all, all) = recorder(None);
+        let (mut modal, settings) = recorder(Some(MyMsg::scopes_of("Modal", "settings")));
+        MyEventBus::send(MyMsg::Loading(LoadingMsg::Show));
+        MyEventBus::send(MyMsg::Modal(ModalMsg::CloseFromAgent("settings".to_string())));
+        MyEventBus::send(MyMsg::Modal(ModalMsg::CloseFromAgent("about".to_string())));
+        assert_eq!(*all.borrow(), ["Loading", "Modal/settings", "Modal/about"]);
+        assert_eq!(*settings.borrow(), ["Modal/settings"]);
+        // the scopes are changed by the bridge, not sent
+        modal.send(MyMsg::Subscribe(vec!["Loading".to_string()]));
+        assert_eq!(all.borrow().len(), 3);
+        MyEventBus::dispatcher().send(MyMsg::Loading(LoadingMsg::Hide));
+        assert_eq!(*settings.borrow(), ["Modal/settings", "Loading"]);
+        drop(modal);
+        MyEventBus::send(MyMsg::Loading(LoadingMsg::Hide));
+        assert_eq!(settings.borrow().len(), 2);
+        assert_eq!(all.borrow().len(), 5);
+    }
+
+    #[test]
+    fn test_bus_order() {
+        // a message sent by a callback reaches every bridge after the current one
+        let _forward = MyEventBus::bridge_scoped(
+            vec!["Loading".to_string()],
+            Rc::new(|_| ALARMS.send(&Alarm { point: 1, level: 1 })),
+        );
+        let (_all, all) = recorder(None);
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let _alarms = {
+            let received = received.clone();
+            ALARMS.bridge(move |alarm| received.borrow_mut().push(alarm))
+        };
+        MyEventBus::send(MyMsg::Loading(LoadingMsg::Show));
+        assert_eq!(*all.borrow(), ["Loading", "alarms"]);
+        assert_eq!(*received.borrow(), [Alarm { point: 1, level: 1 }]);
+    }
+
+    #[test]
+    fn test_request() {
+        use futures::FutureExt;
+
+        let _points = ALARMS.respond(|alarm: Alarm| (alarm.level > 1).then_some(alarm.point * 10));
+        let ask = |level| {
+            let alarm = Alarm { point: 3, level };
+            MyEventBus::ask(BusQuery::Channel("alarms".to_string(), codec::encode(&Cbor, &alarm).unwrap()))
+        };
+        match ask(2).now_or_never() {
+            Some(Some(BusReply::Channel(payload))) => assert_eq!(codec::decode::<u64>(&Cbor, &payload).unwrap(), 30),
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+        // not answered by the channel, the first reply is kept
+        let mut pending = ask(1);
+        assert!((&mut pending).now_or_never().is_none());
+        MyEventBus::reply(pending.id, BusReply::Open(true));
+        MyEventBus::reply(pending.id, BusReply::Open(false));
+        assert!(matches!((&mut pending).now_or_never(), Some(Some(BusReply::Open(true)))));
+        // timed out
+        let mut pending = ask(1);
+        resolve(pending.id, None);
+        assert!(matches!((&mut pending).now_or_never(), Some(None)));
+        // dropped before the reply
+        drop(ask(1));
+        assert!(BUS.with(|bus| bus.borrow().requests.is_empty()));
+    }
+}
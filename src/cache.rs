@@ -0,0 +1,439 @@
+//! Opt-in cache of GET responses, see `set_default_cache_policy` and `RequestBuilder::cache`.
+//!
+//! Entries are keyed by the url and its query parameters except `version`: a response cached
+//! with another `version` is dropped, as are those of every resource under or above the path
+//! of a successful POST, PUT, PATCH or DELETE.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+/// How long a response is used before it is fetched again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachePolicy {
+    ttl: Duration,
+    stale_while_revalidate: Duration,
+    persist: bool,
+}
+
+impl CachePolicy {
+    /// Responses are used for `ttl`, and refetched once expired.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            stale_while_revalidate: Duration::ZERO,
+            persist: false,
+        }
+    }
+
+    /// After the ttl, still use the response for this long while it is fetched again in the background.
+    pub fn set_stale_while_revalidate(&mut self, duration: Duration) -> &mut Self {
+        self.stale_while_revalidate = duration;
+        self
+    }
+
+    /// Also keep the responses in `localStorage`, so they survive a reload.
+    /// Only text bodies, such as JSON, are kept there.
+    pub fn set_persist(&mut self, persist: bool) -> &mut Self {
+        self.persist = persist;
+        self
+    }
+}
+
+/// A response body and its `Content-Type`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Cached {
+    pub body: Vec<u8>,
+    pub content_type: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Lookup {
+    Fresh(Cached),
+    /// To use while fetching it again
+    Stale(Cached),
+    Miss,
+}
+
+/// Parts of a url used by the cache.
+#[derive(Debug, PartialEq)]
+struct CacheKey {
+    /// Path and sorted query parameters without `version`
+    key: String,
+    path: String,
+    version: Option<String>,
+}
+
+impl CacheKey {
+    fn parse(url: &str) -> Self {
+        let url = url.split('#').next().unwrap_or_default();
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+        let mut version = None;
+        let mut params: Vec<_> = query
+            .split('&')
+            .filter(|p| !p.is_empty())
+            .filter(|p| match p.split_once('=') {
+                Some(("version", v)) => {
+                    version = Some(v.to_string());
+                    false
+                }
+                _ => true,
+            })
+            .collect();
+        params.sort_unstable();
+        let key = if params.is_empty() {
+            path.to_string()
+        } else {
+            format!("{}?{}", path, params.join("&"))
+        };
+        Self {
+            key,
+            path: path.to_string(),
+            version,
+        }
+    }
+}
+
+/// Whether one path is the other or one of its parents.
+fn related(a: &str, b: &str) -> bool {
+    let (a, b) = (a.trim_end_matches('/'), b.trim_end_matches('/'));
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    long.strip_prefix(short).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    path: String,
+    version: Option<String>,
+    content_type: Option<String>,
+    /// Milliseconds since the epoch
+    stored: f64,
+    body: Vec<u8>,
+}
+
+#[derive(Default)]
+struct ResponseCache {
+    entries: HashMap<String, Entry>,
+}
+
+impl ResponseCache {
+    fn get(&mut self, key: &CacheKey, policy: &CachePolicy, now: f64) -> Lookup {
+        let Some(entry) = self.entries.get(&key.key) else {
+            return Lookup::Miss;
+        };
+        let age = Duration::from_millis((now - entry.stored).max(0.0) as u64);
+        let cached = Cached {
+            body: entry.body.clone(),
+            content_type: entry.content_type.clone(),
+        };
+        if entry.version != key.version {
+            self.entries.remove(&key.key);
+            Lookup::Miss
+        } else if age < policy.ttl {
+            Lookup::Fresh(cached)
+        } else if age < policy.ttl + policy.stale_while_revalidate {
+            Lookup::Stale(cached)
+        } else {
+            self.entries.remove(&key.key);
+            Lookup::Miss
+        }
+    }
+
+    /// Drops the entries of the same path with another version.
+    fn insert(&mut self, key: &CacheKey, cached: Cached, now: f64) -> Vec<String> {
+        let mut removed: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, e)| e.path == key.path && e.version != key.version)
+            .map(|(k, _)| k.clone())
+            .collect();
+        for k in &removed {
+            self.entries.remove(k);
+        }
+        removed.retain(|k| *k != key.key);
+        let entry = Entry {
+            path: key.path.clone(),
+            version: key.version.clone(),
+            content_type: cached.content_type,
+            stored: now,
+            body: cached.body,
+        };
+        self.entries.insert(key.key.clone(), entry);
+        removed
+    }
+
+    /// Returns the keys removed.
+    fn invalidate(&mut self, path: &str) -> Vec<String> {
+        let removed: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, e)| related(&e.path, path))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for k in &removed {
+            self.entries.remove(k);
+        }
+        removed
+    }
+}
+
+thread_local! {
+    static DEFAULT_CACHE: RefCell<Option<CachePolicy>> = const { RefCell::new(None) };
+    static CACHE: RefCell<ResponseCache> = RefCell::new(ResponseCache::default());
+    /// Keys being fetched again for a stale entry
+    static REVALIDATING: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+}
+
+/// Cache policy of GET requests without their own, including the `async_ws_get*` helpers.
+/// None by default.
+pub fn set_default_cache_policy(policy: Option<CachePolicy>) {
+    DEFAULT_CACHE.with(|p| *p.borrow_mut() = policy);
+}
+
+pub(crate) fn default_cache_policy() -> Option<CachePolicy> {
+    DEFAULT_CACHE.with(|p| p.borrow().clone())
+}
+
+/// Drop the cached responses of `path`, of the resources under it and of its parents.
+/// Done for every successful POST, PUT, PATCH and DELETE.
+pub fn invalidate_cache(path: &str) {
+    let path = CacheKey::parse(path).path;
+    let removed = CACHE.with(|c| c.borrow_mut().invalidate(&path));
+    storage::remove(&removed);
+    storage::remove_where(|p| related(p, &path));
+}
+
+/// Drop every cached response, e.g. when the user logs out.
+pub fn clear_cache() {
+    CACHE.with(|c| c.borrow_mut().entries.clear());
+    storage::remove_where(|_| true);
+}
+
+pub(crate) fn lookup(url: &str, policy: &CachePolicy) -> Lookup {
+    let key = CacheKey::parse(url);
+    let now = js_sys::Date::now();
+    let missing = CACHE.with(|c| !c.borrow().entries.contains_key(&key.key));
+    if missing && policy.persist {
+        if let Some(entry) = storage::get(&key.key) {
+            CACHE.with(|c| c.borrow_mut().entries.insert(key.key.clone(), entry));
+        }
+    }
+    let lookup = CACHE.with(|c| c.borrow_mut().get(&key, policy, now));
+    if lookup == Lookup::Miss && policy.persist {
+        storage::remove(&[key.key]);
+    }
+    lookup
+}
+
+pub(crate) fn store(url: &str, policy: &CachePolicy, cached: Cached) {
+    let key = CacheKey::parse(url);
+    let now = js_sys::Date::now();
+    let removed = CACHE.with(|c| c.borrow_mut().insert(&key, cached, now));
+    storage::remove(&removed);
+    if policy.persist {
+        if let Some(entry) = CACHE.with(|c| c.borrow().entries.get(&key.key).cloned()) {
+            storage::set(&key.key, &entry);
+        }
+    }
+}
+
+/// Whether the caller should fetch `url` again, false if it is already being fetched.
+pub(crate) fn start_revalidate(url: &str) -> bool {
+    REVALIDATING.with(|r| r.borrow_mut().insert(url.to_string()))
+}
+
+pub(crate) fn finish_revalidate(url: &str) {
+    REVALIDATING.with(|r| r.borrow_mut().remove(url));
+}
+
+/// Entries kept in `localStorage`, the body as text. The persisted keys are listed in an
+/// index, so mutations only touch `localStorage` for the entries they invalidate.
+mod storage {
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize, Serialize};
+    use web_sys::Storage;
+
+    use super::Entry;
+
+    const PREFIX: &str = "yew-bulma-cache:";
+    const INDEX_KEY: &str = "yew-bulma-cache-index";
+
+    thread_local! {
+        /// Path of every persisted key, read from `localStorage` on first use
+        static INDEX: RefCell<Option<BTreeMap<String, String>>> = const { RefCell::new(None) };
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Stored {
+        path: String,
+        version: Option<String>,
+        content_type: Option<String>,
+        stored: f64,
+        body: String,
+    }
+
+    fn local_storage() -> Option<Storage> {
+        web_sys::window()?.local_storage().ok().flatten()
+    }
+
+    fn with_index<R>(f: impl FnOnce(&mut BTreeMap<String, String>) -> R) -> R {
+        INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            let index = index.get_or_insert_with(|| {
+                local_storage()
+                    .and_then(|s| s.get_item(INDEX_KEY).ok().flatten())
+                    .and_then(|v| serde_json::from_str(&v).ok())
+                    .unwrap_or_default()
+            });
+            f(index)
+        })
+    }
+
+    fn save_index(storage: &Storage, index: &BTreeMap<String, String>) {
+        let result = if index.is_empty() {
+            storage.remove_item(INDEX_KEY)
+        } else {
+            storage.set_item(INDEX_KEY, &serde_json::to_string(index).unwrap_or_default())
+        };
+        if let Err(e) = result {
+            log::warn!("!!Failed to save the cache index in localStorage, {:?}", e);
+        }
+    }
+
+    fn parse(value: &str) -> Option<Entry> {
+        let stored: Stored = serde_json::from_str(value).ok()?;
+        Some(Entry {
+            path: stored.path,
+            version: stored.version,
+            content_type: stored.content_type,
+            stored: stored.stored,
+            body: stored.body.into_bytes(),
+        })
+    }
+
+    pub(super) fn get(key: &str) -> Option<Entry> {
+        if !with_index(|index| index.contains_key(key)) {
+            return None;
+        }
+        parse(&local_storage()?.get_item(&format!("{}{}", PREFIX, key)).ok()??)
+    }
+
+    pub(super) fn set(key: &str, entry: &Entry) {
+        let (Some(storage), Ok(body)) = (local_storage(), String::from_utf8(entry.body.clone())) else {
+            return;
+        };
+        let stored = Stored {
+            path: entry.path.clone(),
+            version: entry.version.clone(),
+            content_type: entry.content_type.clone(),
+            stored: entry.stored,
+            body,
+        };
+        if let Ok(value) = serde_json::to_string(&stored) {
+            if let Err(e) = storage.set_item(&format!("{}{}", PREFIX, key), &value) {
+                log::warn!("!!Failed to cache {} in localStorage, {:?}", key, e);
+                return;
+            }
+            with_index(|index| {
+                if index.insert(key.to_string(), entry.path.clone()).is_none() {
+                    save_index(&storage, index);
+                }
+            });
+        }
+    }
+
+    pub(super) fn remove(keys: &[String]) {
+        remove_keys(|key, _| keys.iter().any(|k| k == key));
+    }
+
+    /// Removes the entries whose path matches `f`.
+    pub(super) fn remove_where<F: Fn(&str) -> bool>(f: F) {
+        remove_keys(|_, path| f(path));
+    }
+
+    fn remove_keys<F: Fn(&str, &str) -> bool>(f: F) {
+        with_index(|index| {
+            let keys: Vec<String> = index.iter().filter(|(k, p)| f(k, p)).map(|(k, _)| k.clone()).collect();
+            if keys.is_empty() {
+                return;
+            }
+            let Some(storage) = local_storage() else {
+                return;
+            };
+            for key in keys {
+                let _ = storage.remove_item(&format!("{}{}", PREFIX, key));
+                index.remove(&key);
+            }
+            save_index(&storage, index);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cached(body: &str) -> Cached {
+        Cached {
+            body: body.as_bytes().to_vec(),
+            content_type: Some("application/json".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_key() {
+        let key = CacheKey::parse("/api/v1/points?page=2&version=7&kind=ai#top");
+        assert_eq!(key.key, "/api/v1/points?kind=ai&page=2");
+        assert_eq!(key.path, "/api/v1/points");
+        assert_eq!(key.version.as_deref(), Some("7"));
+        assert_eq!(CacheKey::parse("/api/v1/points?kind=ai&page=2").key, key.key);
+        assert_eq!(CacheKey::parse("/api/v1/points").version, None);
+        assert!(related("/api/v1/points", "/api/v1/points/3"));
+        assert!(related("/api/v1/points/", "/api/v1/points"));
+        assert!(!related("/api/v1/points", "/api/v1/pointsets"));
+        assert!(!related("/api/v1/points/3", "/api/v1/points/4"));
+    }
+
+    #[test]
+    fn test_ttl() {
+        let mut cache = ResponseCache::default();
+        let mut policy = CachePolicy::new(Duration::from_secs(10));
+        policy.set_stale_while_revalidate(Duration::from_secs(20));
+        let key = CacheKey::parse("/api/v1/points");
+        assert_eq!(cache.get(&key, &policy, 0.0), Lookup::Miss);
+        cache.insert(&key, cached("[1]"), 1000.0);
+        assert_eq!(cache.get(&key, &policy, 5000.0), Lookup::Fresh(cached("[1]")));
+        assert_eq!(cache.get(&key, &policy, 15000.0), Lookup::Stale(cached("[1]")));
+        assert_eq!(cache.get(&key, &policy, 31000.0), Lookup::Miss);
+        assert!(cache.entries.is_empty());
+    }
+
+    #[test]
+    fn test_invalidation() {
+        let mut cache = ResponseCache::default();
+        let policy = CachePolicy::new(Duration::from_secs(10));
+        let v1 = CacheKey::parse("/api/v1/points?version=1");
+        let page = CacheKey::parse("/api/v1/points?page=2&version=1");
+        cache.insert(&v1, cached("[1]"), 0.0);
+        cache.insert(&page, cached("[2]"), 0.0);
+        // a new version replaces every page of the resource
+        let v2 = CacheKey::parse("/api/v1/points?version=2");
+        assert_eq!(cache.get(&v2, &policy, 0.0), Lookup::Miss);
+        let removed = cache.insert(&v2, cached("[3]"), 0.0);
+        assert_eq!(removed, vec![page.key.clone()]);
+        assert_eq!(cache.get(&v2, &policy, 0.0), Lookup::Fresh(cached("[3]")));
+        assert_eq!(cache.get(&page, &policy, 0.0), Lookup::Miss);
+
+        let other = CacheKey::parse("/api/v1/users");
+        cache.insert(&other, cached("[]"), 0.0);
+        let point = CacheKey::parse("/api/v1/points/3");
+        cache.insert(&point, cached("{}"), 0.0);
+        // a mutation of one point drops the point and the list
+        let mut removed = cache.invalidate("/api/v1/points/3");
+        removed.sort();
+        assert_eq!(removed, vec!["/api/v1/points".to_string(), "/api/v1/points/3".to_string()]);
+        assert_eq!(cache.get(&other, &policy, 0.0), Lookup::Fresh(cached("[]")));
+    }
+}
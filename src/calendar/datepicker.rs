@@ -6,10 +6,9 @@ use wasm_bindgen::prelude::*;
 use wasm_bindgen::{JsCast, JsValue};
 use web_sys::Element;
 use yew::prelude::*;
-use yew_agent::{Dispatched, Dispatcher};
 
 use crate::*;
-use crate::{MyEventBus, MyMsg};
+use crate::{BusDispatcher, MyEventBus, MyMsg};
 
 pub const UI_TEXT_IDS: [&str; 7] = [
     "ok",
@@ -85,7 +84,7 @@ pub enum Msg {
 }
 
 pub struct DatePicker {
-    event_bus: Dispatcher<MyEventBus>,
+    event_bus: BusDispatcher,
     ele_ref: NodeRef,
     listener: Option<Closure<dyn Fn(JsValue)>>,
 }
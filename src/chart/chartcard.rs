@@ -4,7 +4,6 @@ use log::debug;
 use serde::{Deserialize, Serialize};
 use web_sys::Element;
 use yew::prelude::*;
-use yew_agent::{Bridge, Bridged};
 
 use crate::chart::timeseries::{
     create_series_floats, create_series_ints, create_series_mix, render_ts_floats, render_ts_ints,
@@ -37,7 +36,7 @@ pub struct ChartView {
     average_value: Option<f64>,
     sum_value: Option<f64>,
     #[allow(dead_code)]
-    subscription: Box<dyn Bridge<MyEventBus>>,
+    subscription: BusBridge,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -58,6 +57,8 @@ pub enum Msg {
     ShowLegend,
     ChangeTemplate(String, String),
     UpdateSeries(String, ChartSeries),
+    /// A query of the bus, see `BusQuery::ChartSeries`
+    Request(u64, BusQuery),
     None,
 }
 
@@ -116,11 +117,12 @@ impl Component for ChartView {
             move |msg| {
                 link.send_message(match msg {
                     MyMsg::ChartMsg(message) => message,
+                    MyMsg::Request(id, query) => Msg::Request(id, query),
                     _ => Msg::None,
                 })
             }
         };
-        let subscription = MyEventBus::bridge(std::rc::Rc::new(cb));
+        let subscription = MyEventBus::bridge_scoped(MyMsg::scopes_of("Chart", &ctx.props().id), std::rc::Rc::new(cb));
         Self {
             chart: None,
             data: None,
@@ -289,7 +291,10 @@ impl Component for ChartView {
                     self.chart = None;
                 }
             }
-            Msg::None => {}
+            Msg::Request(id, BusQuery::ChartSeries(_)) => {
+                MyEventBus::reply(id, BusReply::Series(self.data.clone()));
+            }
+            Msg::Request(..) | Msg::None => {}
         }
         false
     }
@@ -0,0 +1,203 @@
+//! Payload codecs used by `MySocket` to encode outbound and decode inbound messages.
+//!
+//! A `Codec` works on `serde_cbor::Value`, which covers the whole serde data model,
+//! so implementations for other formats only translate between bytes and values.
+//! Map keys, such as struct fields, are written in sorted order.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use derive_more::Display;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_cbor::Value;
+
+#[derive(Debug, Clone, PartialEq, Display)]
+pub enum CodecError {
+    #[display("failed to encode payload: {_0}")]
+    Encode(String),
+    #[display("failed to decode payload: {_0}")]
+    Decode(String),
+}
+
+impl std::error::Error for CodecError {}
+
+pub trait Codec {
+    fn encode(&self, value: &Value) -> Result<Vec<u8>, CodecError>;
+    fn decode(&self, payload: &[u8]) -> Result<Value, CodecError>;
+}
+
+/// Serialize `value` into a payload with `codec`.
+pub fn encode<T: Serialize + ?Sized>(codec: &dyn Codec, value: &T) -> Result<Vec<u8>, CodecError> {
+    let value = serde_cbor::value::to_value(value).map_err(|e| CodecError::Encode(e.to_string()))?;
+    codec.encode(&value)
+}
+
+/// Deserialize a payload written by `codec`.
+pub fn decode<T: DeserializeOwned>(codec: &dyn Codec, payload: &[u8]) -> Result<T, CodecError> {
+    let value = codec.decode(payload)?;
+    serde_cbor::value::from_value(value).map_err(|e| CodecError::Decode(e.to_string()))
+}
+
+/// The default codec.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cbor;
+
+impl Codec for Cbor {
+    fn encode(&self, value: &Value) -> Result<Vec<u8>, CodecError> {
+        serde_cbor::to_vec(value).map_err(|e| CodecError::Encode(e.to_string()))
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Value, CodecError> {
+        serde_cbor::from_slice(payload).map_err(|e| CodecError::Decode(e.to_string()))
+    }
+}
+
+/// Maps must have string keys, bytes are written as arrays of numbers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Codec for Json {
+    fn encode(&self, value: &Value) -> Result<Vec<u8>, CodecError> {
+        serde_json::to_vec(value).map_err(|e| CodecError::Encode(e.to_string()))
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Value, CodecError> {
+        serde_json::from_slice(payload).map_err(|e| CodecError::Decode(e.to_string()))
+    }
+}
+
+/// The payload is sent as it is: byte arrays like `Vec<u8>` and strings are accepted,
+/// inbound payloads decode into `Vec<u8>`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Raw;
+
+impl Codec for Raw {
+    fn encode(&self, value: &Value) -> Result<Vec<u8>, CodecError> {
+        match value {
+            Value::Bytes(b) => Ok(b.clone()),
+            Value::Text(s) => Ok(s.as_bytes().to_vec()),
+            Value::Array(items) => items
+                .iter()
+                .map(|item| match item {
+                    Value::Integer(i) => u8::try_from(*i).ok(),
+                    _ => None,
+                })
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(|| CodecError::Encode("raw payload must be an array of bytes".to_string())),
+            _ => Err(CodecError::Encode("raw payload must be bytes or a string".to_string())),
+        }
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Value, CodecError> {
+        Ok(Value::Array(payload.iter().map(|b| Value::Integer(*b as i128)).collect()))
+    }
+}
+
+/// A default codec and the codecs of topics using another one.
+#[derive(Clone)]
+pub struct Codecs {
+    default: Rc<dyn Codec>,
+    topics: HashMap<String, Rc<dyn Codec>>,
+}
+
+impl Default for Codecs {
+    fn default() -> Self {
+        Self::new(Rc::new(Cbor))
+    }
+}
+
+impl Codecs {
+    pub fn new(default: Rc<dyn Codec>) -> Self {
+        Self {
+            default,
+            topics: HashMap::new(),
+        }
+    }
+
+    pub fn set_default(&mut self, codec: Rc<dyn Codec>) -> &mut Self {
+        self.default = codec;
+        self
+    }
+
+    pub fn set_topic<S: Into<String>>(&mut self, topic: S, codec: Rc<dyn Codec>) -> &mut Self {
+        self.topics.insert(topic.into(), codec);
+        self
+    }
+
+    pub fn remove_topic(&mut self, topic: &str) -> &mut Self {
+        self.topics.remove(topic);
+        self
+    }
+
+    pub fn get(&self, topic: &str) -> Rc<dyn Codec> {
+        self.topics.get(topic).unwrap_or(&self.default).clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Mode {
+        Auto,
+        Manual(f64),
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Measure {
+        id: u64,
+        name: String,
+        values: Vec<f64>,
+        mode: Mode,
+        limit: Option<i32>,
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let measure = Measure {
+            id: 7,
+            name: "p1".to_string(),
+            values: vec![1.5, -2.0],
+            mode: Mode::Manual(0.25),
+            limit: None,
+        };
+        let codecs: [Rc<dyn Codec>; 2] = [Rc::new(Cbor), Rc::new(Json)];
+        for codec in codecs {
+            let payload = encode(codec.as_ref(), &measure).unwrap();
+            assert_eq!(decode::<Measure>(codec.as_ref(), &payload).unwrap(), measure);
+        }
+        // readable by peers using the formats directly
+        let payload = encode(&Cbor, &measure).unwrap();
+        assert_eq!(serde_cbor::from_slice::<Measure>(&payload).unwrap(), measure);
+        let payload = encode(&Json, &measure).unwrap();
+        assert_eq!(serde_json::from_slice::<Measure>(&payload).unwrap(), measure);
+        let payload = serde_json::to_vec(&measure).unwrap();
+        assert_eq!(decode::<Measure>(&Json, &payload).unwrap(), measure);
+        assert!(decode::<Measure>(&Json, b"{\"id\":1}").is_err());
+    }
+
+    #[test]
+    fn test_raw() {
+        assert_eq!(encode(&Raw, &vec![1u8, 2, 255]).unwrap(), vec![1, 2, 255]);
+        assert_eq!(encode(&Raw, "on").unwrap(), b"on".to_vec());
+        assert!(encode(&Raw, &vec![256u16]).is_err());
+        assert!(encode(&Raw, &1.5).is_err());
+        assert_eq!(decode::<Vec<u8>>(&Raw, &[0, 42]).unwrap(), vec![0, 42]);
+    }
+
+    #[test]
+    fn test_codecs() {
+        let mut codecs = Codecs::default();
+        codecs.set_topic("plant/1/json", Rc::new(Json));
+        let payload = encode(codecs.get("plant/1/json").as_ref(), &[1, 2]).unwrap();
+        assert_eq!(payload, b"[1,2]".to_vec());
+        let payload = encode(codecs.get("plant/1/cbor").as_ref(), &[1, 2]).unwrap();
+        assert_eq!(payload, serde_cbor::to_vec(&[1, 2]).unwrap());
+        codecs.remove_topic("plant/1/json").set_default(Rc::new(Json));
+        assert_eq!(encode(codecs.get("plant/1/json").as_ref(), &1).unwrap(), b"1".to_vec());
+    }
+}
@@ -0,0 +1,493 @@
+//! Alert, confirm and prompt dialogs shown by `MyDialog` with a `ModalCard`, one at a time
+//! in the order they are asked.
+//!
+//! ```ignore
+//! // once in the page, next to MyLoading
+//! html! { <MyDialog text_map={text_map} /> }
+//!
+//! my_confirm("Delete the point?", move |ok| {
+//!     if ok {
+//!         link.send_message(Msg::Delete);
+//!     }
+//! });
+//! let name = Dialog::prompt("Name of the new folder")
+//!     .validate(|s| if s.contains('/') { Err("No / in a name".to_string()) } else { Ok(()) })
+//!     .open()
+//!     .await;
+//! ```
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Poll, Waker};
+
+use gloo_utils::window;
+use web_sys::{HtmlInputElement, KeyboardEvent};
+use yew::prelude::*;
+
+use crate::{BusBridge, ModalCard, ModalMsg, MyEventBus, MyMsg};
+
+pub const UI_TEXT_IDS: [&str; 5] = ["alert", "confirm", "prompt", "ok", "cancel"];
+
+/// Id of the `ModalCard` of `MyDialog`.
+pub const DIALOG_MODAL_ID: &str = "my-dialog";
+
+type Validate = Rc<dyn Fn(&str) -> Result<(), String>>;
+type Answer = Box<dyn FnOnce(Option<String>)>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DialogKind {
+    Alert,
+    Confirm,
+    Prompt,
+}
+
+/// A dialog to show with `show` or `open`.
+#[derive(Clone)]
+pub struct Dialog {
+    kind: DialogKind,
+    message: String,
+    title: Option<String>,
+    value: String,
+    placeholder: String,
+    validate: Option<Validate>,
+}
+
+impl Dialog {
+    fn new(kind: DialogKind, message: String) -> Self {
+        Self {
+            kind,
+            message,
+            title: None,
+            value: String::new(),
+            placeholder: String::new(),
+            validate: None,
+        }
+    }
+
+    pub fn alert<S: Into<String>>(message: S) -> Self {
+        Self::new(DialogKind::Alert, message.into())
+    }
+
+    pub fn confirm<S: Into<String>>(message: S) -> Self {
+        Self::new(DialogKind::Confirm, message.into())
+    }
+
+    pub fn prompt<S: Into<String>>(message: S) -> Self {
+        Self::new(DialogKind::Prompt, message.into())
+    }
+
+    pub fn kind(&self) -> DialogKind {
+        self.kind
+    }
+
+    /// By default the text of the kind of the dialog in the `text_map` of `MyDialog`.
+    pub fn title<S: Into<String>>(mut self, title: S) -> Self {
+        self.title = Some(title.into());
+        self
+    }
+
+    /// Initial text of a prompt.
+    pub fn value<S: Into<String>>(mut self, value: S) -> Self {
+        self.value = value.into();
+        self
+    }
+
+    pub fn placeholder<S: Into<String>>(mut self, placeholder: S) -> Self {
+        self.placeholder = placeholder.into();
+        self
+    }
+
+    /// Check the text of a prompt when it is confirmed, the dialog stays open and shows the
+    /// error until the text is valid or the dialog is cancelled.
+    pub fn validate<F: Fn(&str) -> Result<(), String> + 'static>(mut self, validate: F) -> Self {
+        self.validate = Some(Rc::new(validate));
+        self
+    }
+
+    fn check(&self, value: &str) -> Result<(), String> {
+        match &self.validate {
+            Some(validate) => validate(value),
+            None => Ok(()),
+        }
+    }
+
+    /// Show the dialog once those asked before are closed. `callback` is given None if it is
+    /// cancelled, else the text of a prompt, empty for the other kinds. Without a `MyDialog`
+    /// in the page, the dialogs of the browser are used.
+    pub fn show<F: FnOnce(Option<String>) + 'static>(self, callback: F) {
+        let host = DIALOGS.with(|d| d.borrow().host.clone());
+        match host {
+            Some((_, host)) => {
+                DIALOGS.with(|d| d.borrow_mut().push(self, Box::new(callback)));
+                host.emit(());
+            }
+            None => callback(self.ask_browser()),
+        }
+    }
+
+    /// `show` as a future, the dialog is queued when this is called.
+    pub fn open(self) -> impl Future<Output = Option<String>> {
+        let slot = Rc::new(RefCell::new(AnswerSlot::default()));
+        let answer = AnswerFuture { slot: slot.clone() };
+        self.show(move |text| {
+            let waker = {
+                let mut slot = slot.borrow_mut();
+                slot.answer = Some(text);
+                slot.waker.take()
+            };
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        });
+        answer
+    }
+
+    fn ask_browser(&self) -> Option<String> {
+        let window = window();
+        match self.kind {
+            DialogKind::Alert => {
+                let _ = window.alert_with_message(&self.message);
+                Some(String::new())
+            }
+            DialogKind::Confirm => window.confirm_with_message(&self.message).unwrap_or(false).then(String::new),
+            DialogKind::Prompt => {
+                let (mut message, mut value) = (self.message.clone(), self.value.clone());
+                loop {
+                    let text = window.prompt_with_message_and_default(&message, &value).ok().flatten()?;
+                    match self.check(&text) {
+                        Ok(()) => return Some(text),
+                        Err(e) => {
+                            message = format!("{}\n{}", self.message, e);
+                            value = text;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct AnswerSlot {
+    answer: Option<Option<String>>,
+    waker: Option<Waker>,
+}
+
+struct AnswerFuture {
+    slot: Rc<RefCell<AnswerSlot>>,
+}
+
+impl Future for AnswerFuture {
+    type Output = Option<String>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
+        let mut slot = self.slot.borrow_mut();
+        match slot.answer.take() {
+            Some(answer) => Poll::Ready(answer),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+struct Asked {
+    id: u64,
+    dialog: Dialog,
+    answer: Answer,
+}
+
+#[derive(Default)]
+struct Dialogs {
+    next_id: u64,
+    queue: VecDeque<Asked>,
+    /// The mounted `MyDialog` and the callback re-rendering it
+    host: Option<(u64, Callback<()>)>,
+}
+
+impl Dialogs {
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn push(&mut self, dialog: Dialog, answer: Answer) -> u64 {
+        let id = self.next_id();
+        self.queue.push_back(Asked { id, dialog, answer });
+        id
+    }
+
+    /// The dialog shown.
+    fn current(&self) -> Option<(u64, Dialog)> {
+        self.queue.front().map(|a| (a.id, a.dialog.clone()))
+    }
+
+    /// Removes the dialog `id` if it is the one shown, the answer is given by the caller
+    /// once the registry is released, as it may ask another dialog.
+    fn finish(&mut self, id: u64) -> Option<Answer> {
+        if self.queue.front()?.id != id {
+            return None;
+        }
+        self.queue.pop_front().map(|a| a.answer)
+    }
+
+    /// Removes the `MyDialog` with this id if it is the one used, with the dialogs it was to show.
+    fn unmount(&mut self, host: u64) -> Vec<Answer> {
+        if !self.host.as_ref().is_some_and(|(id, _)| *id == host) {
+            return Vec::new();
+        }
+        self.host = None;
+        self.queue.drain(..).map(|a| a.answer).collect()
+    }
+}
+
+thread_local! {
+    static DIALOGS: RefCell<Dialogs> = RefCell::new(Dialogs::default());
+}
+
+#[derive(Clone, Debug, PartialEq, Properties)]
+pub struct Props {
+    #[prop_or_default]
+    pub text_map: HashMap<String, String>,
+    #[prop_or_default]
+    pub width: String,
+}
+
+pub enum Msg {
+    Changed,
+    Ok(u64),
+    Cancel(u64),
+    /// Closed by the user with the `ModalCard`
+    Closed(u64),
+    /// Every modal was closed from the bus, the dialog shown is opened again
+    Hidden,
+}
+
+/// Shows the dialogs of `Dialog`, `my_alert`, `my_confirm` and `my_prompt`.
+/// Only one should be mounted, the last one mounted is used.
+pub struct MyDialog {
+    id: u64,
+    /// Whether the `ModalCard` was opened
+    open: bool,
+    input_ref: NodeRef,
+    /// Error of the text of the prompt shown
+    error: Option<String>,
+    _subscription: BusBridge,
+}
+
+impl Component for MyDialog {
+    type Message = Msg;
+    type Properties = Props;
+
+    fn create(ctx: &Context<Self>) -> Self {
+        let host = ctx.link().callback(|_| Msg::Changed);
+        let id = DIALOGS.with(|d| {
+            let mut d = d.borrow_mut();
+            let id = d.next_id();
+            d.host = Some((id, host));
+            id
+        });
+        let link = ctx.link().clone();
+        let _subscription = MyEventBus::bridge_scoped(vec!["Modal".to_string()], Rc::new(move |msg| {
+            if let MyMsg::Modal(ModalMsg::Close) = msg {
+                link.send_message(Msg::Hidden);
+            }
+        }));
+        Self {
+            id,
+            open: false,
+            input_ref: NodeRef::default(),
+            error: None,
+            _subscription,
+        }
+    }
+
+    fn update(&mut self, _: &Context<Self>, msg: Self::Message) -> bool {
+        match msg {
+            Msg::Changed => true,
+            Msg::Ok(id) => {
+                let Some((current, dialog)) = DIALOGS.with(|d| d.borrow().current()) else {
+                    return false;
+                };
+                if current != id {
+                    return false;
+                }
+                let text = match dialog.kind {
+                    DialogKind::Prompt => {
+                        let text = self.input_ref.cast::<HtmlInputElement>().map(|i| i.value()).unwrap_or_default();
+                        if let Err(e) = dialog.check(&text) {
+                            self.error = Some(e);
+                            return true;
+                        }
+                        text
+                    }
+                    _ => String::new(),
+                };
+                self.finish(id, Some(text))
+            }
+            Msg::Cancel(id) => self.finish(id, None),
+            Msg::Closed(id) => {
+                self.open = false;
+                self.finish(id, None)
+            }
+            Msg::Hidden => {
+                self.open = false;
+                true
+            }
+        }
+    }
+
+    fn view(&self, ctx: &Context<Self>) -> Html {
+        let link = ctx.link();
+        let Some((id, dialog)) = DIALOGS.with(|d| d.borrow().current()) else {
+            return html! {
+                <ModalCard id={DIALOG_MODAL_ID} title={String::new()} width={ctx.props().width.clone()} />
+            };
+        };
+        let title = match &dialog.title {
+            Some(title) => title.clone(),
+            None => self.get_text(ctx, match dialog.kind {
+                DialogKind::Alert => "alert",
+                DialogKind::Confirm => "confirm",
+                DialogKind::Prompt => "prompt",
+            }),
+        };
+        let onkeydown = link.batch_callback(move |e: KeyboardEvent| match e.key().as_str() {
+            "Enter" => Some(Msg::Ok(id)),
+            "Escape" => Some(Msg::Cancel(id)),
+            _ => None,
+        });
+        let input_classes = classes!("input", self.error.as_ref().map(|_| "is-danger"));
+        let body = html! {
+            <>
+            <p style="white-space: pre-line;">{ dialog.message.clone() }</p>
+            if dialog.kind == DialogKind::Prompt {
+                <div class="field mt-3">
+                    <div class="control">
+                        <input key={id.to_string()} ref={self.input_ref.clone()} class={input_classes} type="text"
+                            value={dialog.value.clone()} placeholder={dialog.placeholder.clone()} {onkeydown} />
+                    </div>
+                    if let Some(error) = &self.error {
+                        <p class="help is-danger">{ error.clone() }</p>
+                    }
+                </div>
+            }
+            </>
+        };
+        let footer = html! {
+            <div class="buttons">
+                <button class="button is-primary" onclick={link.callback(move |_| Msg::Ok(id))}>
+                    { self.get_text(ctx, "ok") }
+                </button>
+                if dialog.kind != DialogKind::Alert {
+                    <button class="button" onclick={link.callback(move |_| Msg::Cancel(id))}>
+                        { self.get_text(ctx, "cancel") }
+                    </button>
+                }
+            </div>
+        };
+        html! {
+            <ModalCard id={DIALOG_MODAL_ID} {title} {body} {footer} width={ctx.props().width.clone()}
+                on_close={link.callback(move |_| Msg::Closed(id))} />
+        }
+    }
+
+    fn rendered(&mut self, _: &Context<Self>, _: bool) {
+        // the ModalCard is created by the first render
+        let open = DIALOGS.with(|d| !d.borrow().queue.is_empty());
+        if open != self.open {
+            self.open = open;
+            let id = DIALOG_MODAL_ID.to_string();
+            let msg = if open { ModalMsg::OpenFromAgent(id) } else { ModalMsg::CloseFromAgent(id) };
+            MyEventBus::send(MyMsg::Modal(msg));
+        }
+    }
+
+    fn destroy(&mut self, _: &Context<Self>) {
+        // the dialogs left are cancelled, a dialog asked by their callbacks goes to the browser
+        let answers = DIALOGS.with(|d| d.borrow_mut().unmount(self.id));
+        for answer in answers {
+            answer(None);
+        }
+    }
+}
+
+impl MyDialog {
+    fn finish(&mut self, id: u64, text: Option<String>) -> bool {
+        let Some(answer) = DIALOGS.with(|d| d.borrow_mut().finish(id)) else {
+            return false;
+        };
+        self.error = None;
+        answer(text);
+        true
+    }
+
+    fn get_text(&self, ctx: &Context<Self>, key: &str) -> String {
+        if let Some(s) = ctx.props().text_map.get(key) {
+            s.clone()
+        } else {
+            key.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue() {
+        let answers = Rc::new(RefCell::new(Vec::new()));
+        let answer = |name: &'static str| -> Answer {
+            let answers = answers.clone();
+            Box::new(move |text| answers.borrow_mut().push((name, text)))
+        };
+        let mut dialogs = Dialogs::default();
+        let first = dialogs.push(Dialog::confirm("Delete?"), answer("first"));
+        let second = dialogs.push(Dialog::prompt("Name").value("point"), answer("second"));
+        assert_eq!(dialogs.current().map(|(id, d)| (id, d.kind())), Some((first, DialogKind::Confirm)));
+        // only the dialog shown is answered
+        assert!(dialogs.finish(second).is_none());
+        dialogs.finish(first).unwrap()(None);
+        let (id, dialog) = dialogs.current().unwrap();
+        assert_eq!((id, dialog.value.as_str()), (second, "point"));
+        assert!(dialogs.finish(first).is_none());
+        dialogs.finish(second).unwrap()(Some("pump".to_string()));
+        assert!(dialogs.current().is_none());
+        assert_eq!(*answers.borrow(), [("first", None), ("second", Some("pump".to_string()))]);
+    }
+
+    #[test]
+    fn test_unmount() {
+        let cancelled = Rc::new(RefCell::new(Vec::new()));
+        let mut dialogs = Dialogs::default();
+        let host = dialogs.next_id();
+        dialogs.host = Some((host, Callback::noop()));
+        for message in ["Delete?", "Name"] {
+            let cancelled = cancelled.clone();
+            dialogs.push(Dialog::confirm(message), Box::new(move |text| cancelled.borrow_mut().push(text)));
+        }
+        // an older MyDialog, replaced by this one, leaves the queue
+        assert!(dialogs.unmount(host + 100).is_empty());
+        for answer in dialogs.unmount(host) {
+            answer(None);
+        }
+        assert!(dialogs.host.is_none() && dialogs.current().is_none());
+        assert_eq!(*cancelled.borrow(), [None, None]);
+    }
+
+    #[test]
+    fn test_validate() {
+        let dialog = Dialog::prompt("Name").validate(|s| match s.trim() {
+            "" => Err("A name is required".to_string()),
+            _ => Ok(()),
+        });
+        assert_eq!(dialog.check(" "), Err("A name is required".to_string()));
+        assert_eq!(dialog.check("pump"), Ok(()));
+        assert_eq!(Dialog::prompt("Name").check(""), Ok(()));
+    }
+}
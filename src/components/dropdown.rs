@@ -1,9 +1,8 @@
 use serde::{Deserialize, Serialize};
 use yew::prelude::*;
-use yew_agent::{Bridge, Bridged};
 
 use crate::elements::button::Button;
-use crate::{MyEventBus, MyMsg};
+use crate::{BusBridge, MyEventBus, MyMsg};
 
 #[derive(Clone, Debug, Properties, PartialEq)]
 pub struct DropdownProps {
@@ -40,7 +39,7 @@ pub enum DropdownMsg {
 /// [https://bulma.io/documentation/components/dropdown/](https://bulma.io/documentation/components/dropdown/)
 pub struct Dropdown {
     #[allow(dead_code)]
-    subscription: Box<dyn Bridge<MyEventBus>>,
+    subscription: BusBridge,
     is_menu_active: bool,
 }
 
@@ -58,7 +57,7 @@ impl Component for Dropdown {
                 })
             }
         };
-        let subscription = MyEventBus::bridge(std::rc::Rc::new(cb));
+        let subscription = MyEventBus::bridge_scoped(MyMsg::scopes_of("Dropdown", &ctx.props().id), std::rc::Rc::new(cb));
         Self {
             subscription,
             is_menu_active: false,
@@ -9,7 +9,6 @@ use serde::{Deserialize, Serialize};
 use web_sys::{Event, HtmlElement};
 use yew::prelude::*;
 use yew::virtual_dom::VNode;
-use yew_agent::{Bridge, Bridged};
 
 use crate::components::mypagination::MyPagination;
 use crate::components::myrownumdp::MyRowNumDP;
@@ -39,6 +38,8 @@ pub enum Msg {
     // 列表分页相关
     RowNumPerPage(usize),
     JumpToPage(usize),
+    // 总线上的查询，回复勾选或选中的路径
+    Request(u64, BusQuery),
     // 无动作
     None,
 }
@@ -108,7 +109,7 @@ pub struct FileTree {
     /// 下一个edge的id
     next_edge_id: usize,
     /// 消息总线
-    _producer: Box<dyn Bridge<MyEventBus>>,
+    _producer: BusBridge,
     /// 不启用路由时的本地路径存储
     local_paths: Vec<String>,
     /// 全局路径（用于摸索匹配时全局检索）
@@ -156,6 +157,7 @@ impl Component for FileTree {
             ctx.props().paths.as_slice()
         };
         let (graph, root_index, next_edge_id) = create_graph(paths);
+        let scopes = MyMsg::scopes_of("FileTree", &tree_id);
         let cb = {
             let link = ctx.link().clone();
             move |msg| {
@@ -168,6 +170,7 @@ impl Component for FileTree {
                             Msg::None
                         }
                     }
+                    MyMsg::Request(id, query) => Msg::Request(id, query),
                     _ => Msg::None,
                 })
             }
@@ -184,7 +187,7 @@ impl Component for FileTree {
             all_paths: paths.to_vec(),
             find_input_ref: NodeRef::default(),
             row_num_per_page: page_now,
-            _producer: MyEventBus::bridge(std::rc::Rc::new(cb)),
+            _producer: MyEventBus::bridge_scoped(scopes, std::rc::Rc::new(cb)),
             current_pagination: 1,
         };
         file_tree.do_expanded_level(ctx);
@@ -392,6 +395,15 @@ impl Component for FileTree {
                 self.current_pagination = n;
                 return true;
             }
+            Msg::Request(id, query) => match query {
+                BusQuery::FileTreeChecked(_) => {
+                    MyEventBus::reply(id, BusReply::Paths(self.checked.iter().cloned().collect()));
+                }
+                BusQuery::FileTreeSelected(_) => {
+                    MyEventBus::reply(id, BusReply::Path(self.selected.clone()));
+                }
+                _ => {}
+            },
             Msg::None => {}
         }
         false
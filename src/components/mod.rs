@@ -16,3 +16,4 @@ pub mod mypagination;
 pub mod myrownumdp;
 pub mod mylist;
 pub mod myloading;
+pub mod dialog;
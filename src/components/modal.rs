@@ -1,14 +1,16 @@
 use serde::{Deserialize, Serialize};
 use yew::prelude::*;
-use yew_agent::{Bridge, Bridged};
 
-use crate::{MyEventBus, MyMsg};
+use crate::{BusBridge, BusQuery, BusReply, MyEventBus, MyMsg};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum ModalMsg {
     Open,
     Close,
     CloseFromAgent(String),
+    OpenFromAgent(String),
+    /// A query of the bus, see `BusQuery::ModalOpen`
+    Request(u64, BusQuery),
     None,
 }
 
@@ -34,7 +36,7 @@ pub struct ModalProps {
 /// in your app for maximum flexibility.
 pub struct Modal {
     #[allow(dead_code)]
-    subscription: Box<dyn Bridge<MyEventBus>>,
+    subscription: BusBridge,
     is_active: bool,
 }
 
@@ -48,11 +50,12 @@ impl Component for Modal {
             move |msg| {
                 link.send_message(match msg {
                     MyMsg::Modal(message) => message,
+                    MyMsg::Request(id, query) => ModalMsg::Request(id, query),
                     _ => ModalMsg::None,
                 })
             }
         };
-        let subscription = MyEventBus::bridge(std::rc::Rc::new(cb));
+        let subscription = MyEventBus::bridge_scoped(MyMsg::scopes_of("Modal", &ctx.props().id), std::rc::Rc::new(cb));
         Self {
             subscription,
             is_active: false,
@@ -72,6 +75,17 @@ impl Component for Modal {
                     self.is_active = false;
                 } 
             }
+            ModalMsg::OpenFromAgent(id) => {
+                if id == ctx.props().id {
+                    self.is_active = true;
+                }
+            }
+            ModalMsg::Request(id, query) => {
+                if let BusQuery::ModalOpen(_) = query {
+                    MyEventBus::reply(id, BusReply::Open(self.is_active));
+                }
+                return false;
+            }
             ModalMsg::None => {}
         }
         true
@@ -132,6 +146,9 @@ pub struct ModalCardProps {
     pub disabled: bool,
     #[prop_or_default]
     pub width: String,
+    /// Called when the user closes the modal, not when it is closed from the bus.
+    #[prop_or_else(Callback::noop)]
+    pub on_close: Callback<()>,
 }
 
 /// A classic modal with a header, body, and footer section.
@@ -142,7 +159,7 @@ pub struct ModalCardProps {
 /// in your app for maximum flexibility.
 pub struct ModalCard {
     #[allow(dead_code)]
-    subscription: Box<dyn Bridge<MyEventBus>>,
+    subscription: BusBridge,
     is_active: bool,
 }
 
@@ -156,11 +173,12 @@ impl Component for ModalCard {
             move |msg| {
                 link.send_message(match msg {
                     MyMsg::Modal(message) => message,
+                    MyMsg::Request(id, query) => ModalMsg::Request(id, query),
                     _ => ModalMsg::None,
                 })
             }
         };
-        let subscription = MyEventBus::bridge(std::rc::Rc::new(cb));
+        let subscription = MyEventBus::bridge_scoped(MyMsg::scopes_of("Modal", &ctx.props().id), std::rc::Rc::new(cb));
         Self {
             subscription,
             is_active: false,
@@ -180,6 +198,17 @@ impl Component for ModalCard {
                     self.is_active = false;
                 } 
             }
+            ModalMsg::OpenFromAgent(id) => {
+                if id == ctx.props().id {
+                    self.is_active = true;
+                }
+            }
+            ModalMsg::Request(id, query) => {
+                if let BusQuery::ModalOpen(_) = query {
+                    MyEventBus::reply(id, BusReply::Open(self.is_active));
+                }
+                return false;
+            }
             ModalMsg::None => {}
         }
         true
@@ -197,7 +226,12 @@ impl Component for ModalCard {
         };
         let (opencb, closecb) = if self.is_active {
             classes.push("is-active");
-            (Callback::noop(), link.callback(|_| ModalMsg::Close))
+            // only the clicks of the user are reported, not the messages of the bus
+            let on_close = ctx.props().on_close.clone();
+            (Callback::noop(), link.callback(move |_| {
+                on_close.emit(());
+                ModalMsg::Close
+            }))
         } else {
             (link.callback(|_| ModalMsg::Open), Callback::noop())
         };
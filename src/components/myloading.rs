@@ -1,5 +1,4 @@
 use yew::prelude::*;
-use yew_agent::{Bridge, Bridged};
 use crate::*;
 
 #[derive(Clone, Debug, PartialEq, Properties)]
@@ -17,7 +16,7 @@ pub enum LoadingMsg {
 pub struct MyLoading {
     is_loading: bool,
     /// 消息总线
-    _subscription: Box<dyn Bridge<MyEventBus>>,
+    _subscription: BusBridge,
 }
 
 impl Component for MyLoading {
@@ -34,7 +33,7 @@ impl Component for MyLoading {
                 })
             }
         };
-        let _subscription = MyEventBus::bridge(std::rc::Rc::new(cb));
+        let _subscription = MyEventBus::bridge_scoped(vec!["Loading".to_string()], std::rc::Rc::new(cb));
         Self {
             _subscription,
             is_loading: ctx.props().is_loading,
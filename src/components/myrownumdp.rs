@@ -1,5 +1,4 @@
 use yew::prelude::*;
-use yew_agent::{Dispatched, Dispatcher};
 
 use crate::*;
 
@@ -23,7 +22,7 @@ pub enum Msg {
 }
 
 pub struct MyRowNumDP {
-    event_bus: Dispatcher<MyEventBus>,
+    event_bus: BusDispatcher,
 }
 
 impl Component for MyRowNumDP {
@@ -0,0 +1,222 @@
+use js_sys::{Array, Reflect, Uint8Array};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{Blob, BlobPropertyBag, Headers, ReadableStreamDefaultReader, Url};
+use yew::Callback;
+
+use crate::fetch::{AbortHandle, FetchError, RequestBuilder};
+use crate::{download_blob, UploadProgress};
+
+/// Bytes of a response body received so far, `total` is zero without `Content-Length`.
+pub type DownloadProgress = UploadProgress;
+
+impl RequestBuilder {
+    /// Save the response body as a file, named `file_name` or else by the `Content-Disposition`
+    /// header or the url. The body is read as it arrives to report the progress, and kept by the
+    /// browser rather than copied into wasm memory. Returns the name of the file.
+    pub async fn download(
+        self,
+        file_name: Option<&str>,
+        on_progress: Callback<DownloadProgress>,
+    ) -> Result<String, FetchError> {
+        let url = self.url();
+        let (resp, exchange) = self.fetch().await?;
+        let headers = resp.headers();
+        let header = |name: &str| headers.get(name).ok().flatten();
+        let name = match file_name {
+            Some(name) => name.to_string(),
+            None => header("Content-Disposition")
+                .and_then(|h| disposition_file_name(&h))
+                .unwrap_or_else(|| url_file_name(&url)),
+        };
+        let total = header("Content-Length").and_then(|s| s.parse().ok()).unwrap_or(0);
+        let parts = Array::new();
+        if let Some(body) = resp.body() {
+            let reader: ReadableStreamDefaultReader = body.get_reader().unchecked_into();
+            let mut loaded = 0;
+            loop {
+                let chunk = JsFuture::from(reader.read()).await.map_err(|e| exchange.error(e))?;
+                if Reflect::get(&chunk, &"done".into())?.as_bool().unwrap_or(true) {
+                    break;
+                }
+                let value: Uint8Array = Reflect::get(&chunk, &"value".into())?.unchecked_into();
+                loaded += value.length() as u64;
+                parts.push(&value);
+                on_progress.emit(DownloadProgress { loaded, total });
+            }
+        }
+        let options = BlobPropertyBag::new();
+        if let Some(content_type) = header("Content-Type") {
+            options.set_type(&content_type);
+        }
+        save_blob(&Blob::new_with_u8_array_sequence_and_options(&parts, &options)?, &name)?;
+        Ok(name)
+    }
+}
+
+fn save_blob(blob: &Blob, file_name: &str) -> Result<(), JsValue> {
+    let url = Url::create_object_url_with_blob(blob)?;
+    download_blob(&url, file_name)
+}
+
+/// Save bytes generated by the program as a file of type `mime`.
+pub fn download_bytes(bytes: &[u8], file_name: &str, mime: &str) -> Result<(), JsValue> {
+    let parts = Array::of1(&Uint8Array::from(bytes));
+    let options = BlobPropertyBag::new();
+    options.set_type(mime);
+    save_blob(&Blob::new_with_u8_array_sequence_and_options(&parts, &options)?, file_name)
+}
+
+/// Save text, e.g. a CSV export, as a UTF-8 file of type `mime`.
+pub fn download_text(text: &str, file_name: &str, mime: &str) -> Result<(), JsValue> {
+    let parts = Array::of1(&JsValue::from_str(text));
+    let options = BlobPropertyBag::new();
+    options.set_type(&format!("{};charset=utf-8", mime));
+    save_blob(&Blob::new_with_str_sequence_and_options(&parts, &options)?, file_name)
+}
+
+/// `async_ws_get` saving the response as a file, see `RequestBuilder::download`.
+pub async fn async_ws_download(
+    url: &str,
+    header: &Headers,
+    file_name: Option<&str>,
+    on_progress: Callback<DownloadProgress>,
+    abort: &AbortHandle,
+) -> Result<String, FetchError> {
+    RequestBuilder::get(url)
+        .headers(header)
+        .abort_handle(abort)
+        .download(file_name, on_progress)
+        .await
+}
+
+/// The file name given by a `Content-Disposition` header, `filename*` before `filename`.
+/// Directories are removed from the name.
+pub fn disposition_file_name(header: &str) -> Option<String> {
+    let mut plain = None;
+    for param in split_params(header) {
+        let Some((key, value)) = param.split_once('=') else {
+            continue;
+        };
+        match key.trim().to_ascii_lowercase().as_str() {
+            // charset'language'percent-encoded
+            "filename*" => {
+                let mut parts = value.trim().splitn(3, '\'');
+                let (Some(charset), Some(_), Some(encoded)) = (parts.next(), parts.next(), parts.next()) else {
+                    continue;
+                };
+                let Some(bytes) = percent_decode(encoded) else {
+                    continue;
+                };
+                let name = if charset.eq_ignore_ascii_case("utf-8") {
+                    String::from_utf8(bytes).ok()
+                } else {
+                    Some(bytes.into_iter().map(char::from).collect())
+                };
+                if let Some(name) = name.as_deref().and_then(base_name) {
+                    return Some(name);
+                }
+            }
+            "filename" => plain = base_name(&unquote(value.trim())),
+            _ => {}
+        }
+    }
+    plain
+}
+
+/// The last segment of the path of `url`, "download" if there is none.
+pub fn url_file_name(url: &str) -> String {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    let path = path.split_once("://").map(|(_, rest)| rest.split_once('/').map_or("", |(_, p)| p)).unwrap_or(path);
+    path.rsplit('/')
+        .find(|s| !s.is_empty())
+        .and_then(|s| percent_decode(s).and_then(|b| String::from_utf8(b).ok()))
+        .and_then(|s| base_name(&s))
+        .unwrap_or_else(|| "download".to_string())
+}
+
+/// Parameters separated by `;` outside of quoted strings.
+fn split_params(header: &str) -> Vec<&str> {
+    let mut params = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in header.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                params.push(&header[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    params.push(&header[start..]);
+    params
+}
+
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(inner) => {
+            let mut out = String::with_capacity(inner.len());
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                out.push(if c == '\\' { chars.next().unwrap_or(c) } else { c });
+            }
+            out
+        }
+        None => value.to_string(),
+    }
+}
+
+fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            out.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            out.push(b);
+        }
+    }
+    Some(out)
+}
+
+/// Without the directories, None if nothing is left.
+fn base_name(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default().trim();
+    (!name.is_empty() && name != "." && name != "..").then(|| name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disposition() {
+        let name = |h: &str| disposition_file_name(h);
+        assert_eq!(name("attachment; filename=\"points.csv\""), Some("points.csv".to_string()));
+        assert_eq!(name("attachment; filename=points.csv"), Some("points.csv".to_string()));
+        assert_eq!(name("attachment; filename=\"a; \\\"b\\\".txt\""), Some("a; \"b\".txt".to_string()));
+        assert_eq!(
+            name("attachment; filename=\"fallback.zip\"; filename*=UTF-8''%E9%85%8D%E7%BD%AE.zip"),
+            Some("配置.zip".to_string())
+        );
+        assert_eq!(name("attachment; FILENAME*=iso-8859-1'en'caf%E9.txt"), Some("café.txt".to_string()));
+        // a broken filename* falls back to filename
+        assert_eq!(name("attachment; filename*=UTF-8''%ZZ; filename=a.txt"), Some("a.txt".to_string()));
+        assert_eq!(name("attachment; filename=\"../../etc/passwd\""), Some("passwd".to_string()));
+        assert_eq!(name("inline"), None);
+        assert_eq!(name("attachment; filename=\"\""), None);
+    }
+
+    #[test]
+    fn test_url_file_name() {
+        assert_eq!(url_file_name("/api/v1/files/report%201.pdf?version=3"), "report 1.pdf");
+        assert_eq!(url_file_name("https://example.com/export/points.csv#top"), "points.csv");
+        assert_eq!(url_file_name("https://example.com"), "download");
+        assert_eq!(url_file_name("/api/v1/archive/"), "archive");
+    }
+}
@@ -0,0 +1,1202 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::time::Duration;
+
+use derive_more::Display;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{AbortController, DomException, FormData, Headers, Request, RequestInit, Response};
+use yew::platform::pinned::oneshot;
+use yew::platform::spawn_local;
+use yew::platform::time::sleep;
+
+use crate::cache::{self, default_cache_policy, invalidate_cache, CachePolicy, Cached, Lookup};
+use crate::{HEADER_PERMISSION_DENIED, HEADER_TOKEN_INVALID};
+
+/// Something wrong has occurred while fetching an external resource.
+#[derive(Debug, Clone, PartialEq, Display)]
+pub enum FetchError {
+    /// The request could not be sent or the response could not be read
+    #[display("{_0}")]
+    Network(String),
+    #[display("request timed out")]
+    Timeout,
+    /// Cancelled by an `AbortHandle` or by a newer request with the same key
+    #[display("request aborted")]
+    Aborted,
+    /// The server answered with the `HEADER_TOKEN_INVALID` header
+    #[display("{HEADER_TOKEN_INVALID}")]
+    TokenInvalid,
+    /// The server answered with the `HEADER_PERMISSION_DENIED` header
+    #[display("{HEADER_PERMISSION_DENIED}")]
+    PermissionDenied,
+    /// Any status but 200, shown as the body or the code if the body is empty
+    #[display("{}", if body.is_empty() { code.to_string() } else { body.clone() })]
+    HttpStatus { code: u16, body: String },
+    #[display("failed to encode request: {_0}")]
+    Encode(String),
+    #[display("failed to decode response: {_0}")]
+    Decode(String),
+}
+
+impl Error for FetchError {}
+
+impl From<JsValue> for FetchError {
+    fn from(value: JsValue) -> Self {
+        let msg = match value.dyn_ref::<js_sys::Error>() {
+            Some(e) => String::from(e.message()),
+            None => value.as_string().unwrap_or_else(|| format!("{:?}", value)),
+        };
+        FetchError::Network(msg)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum Method {
+    #[display("GET")]
+    Get,
+    #[display("HEAD")]
+    Head,
+    #[display("POST")]
+    Post,
+    #[display("PUT")]
+    Put,
+    #[display("PATCH")]
+    Patch,
+    #[display("DELETE")]
+    Delete,
+}
+
+impl Method {
+    /// Whether sending the request twice has the same effect as once.
+    pub fn is_idempotent(&self) -> bool {
+        matches!(self, Method::Get | Method::Head | Method::Put | Method::Delete)
+    }
+}
+
+/// When and how often a failed request is sent again, only idempotent requests by default.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_delay: Duration,
+    max_delay: Duration,
+    retry_on: Vec<u16>,
+    retry_on_network: bool,
+    non_idempotent: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(5),
+            retry_on: vec![502, 503, 504],
+            retry_on_network: true,
+            non_idempotent: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Attempts in total, including the first one.
+    pub fn set_max_attempts(&mut self, max_attempts: u32) -> &mut Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn set_initial_delay(&mut self, delay: Duration) -> &mut Self {
+        self.initial_delay = delay;
+        self
+    }
+
+    pub fn set_max_delay(&mut self, delay: Duration) -> &mut Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// Status codes worth another attempt.
+    pub fn set_retry_on(&mut self, codes: Vec<u16>) -> &mut Self {
+        self.retry_on = codes;
+        self
+    }
+
+    /// Retry when the server could not be reached or did not answer in time.
+    pub fn set_retry_on_network(&mut self, retry: bool) -> &mut Self {
+        self.retry_on_network = retry;
+        self
+    }
+
+    /// Also retry POST and PATCH, only if the server handles duplicates.
+    pub fn set_non_idempotent(&mut self, retry: bool) -> &mut Self {
+        self.non_idempotent = retry;
+        self
+    }
+
+    pub fn should_retry(&self, method: Method, attempt: u32, error: &FetchError) -> bool {
+        if attempt >= self.max_attempts || !(method.is_idempotent() || self.non_idempotent) {
+            return false;
+        }
+        match error {
+            FetchError::Network(_) | FetchError::Timeout => self.retry_on_network,
+            FetchError::HttpStatus { code, .. } => self.retry_on.contains(code),
+            _ => false,
+        }
+    }
+
+    /// Delay after the failed `attempt`, counted from 1, doubled every attempt.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_delay.saturating_mul(factor).min(self.max_delay)
+    }
+}
+
+thread_local! {
+    static DEFAULT_RETRY: RefCell<Option<RetryPolicy>> = const { RefCell::new(None) };
+}
+
+/// Retry policy of requests without their own, including the `async_ws_*` helpers. None by default.
+pub fn set_default_retry_policy(policy: Option<RetryPolicy>) {
+    DEFAULT_RETRY.with(|p| *p.borrow_mut() = policy);
+}
+
+fn default_retry_policy() -> Option<RetryPolicy> {
+    DEFAULT_RETRY.with(|p| p.borrow().clone())
+}
+
+/// Changes every request before it is sent, e.g. to add the auth token.
+pub type RequestInterceptor = Rc<dyn Fn(RequestBuilder) -> RequestBuilder>;
+/// Gets a new token, resolves to false if the user has to log in again.
+pub type RefreshToken = Rc<dyn Fn() -> Pin<Box<dyn Future<Output = bool>>>>;
+
+/// What to do when the server answers with the `HEADER_TOKEN_INVALID` header.
+#[derive(Clone)]
+pub enum TokenInvalidAction {
+    /// Go to the login page at this url, the request fails with `FetchError::TokenInvalid`
+    Redirect(String),
+    /// Refresh the token and send the request once more. Concurrent requests share one refresh.
+    Refresh(RefreshToken),
+}
+
+/// Settings shared by every request, see `set_client_config`.
+#[derive(Clone, Default)]
+pub struct ClientConfig {
+    base_url: Option<String>,
+    interceptors: Vec<RequestInterceptor>,
+    on_token_invalid: Option<TokenInvalidAction>,
+}
+
+impl ClientConfig {
+    /// Prefix of relative urls.
+    pub fn set_base_url<S: Into<String>>(&mut self, base_url: S) -> &mut Self {
+        self.base_url = Some(base_url.into());
+        self
+    }
+
+    /// Interceptors run in the order they are added, before every attempt,
+    /// so a refreshed token is used when the request is sent again.
+    pub fn add_interceptor<F>(&mut self, interceptor: F) -> &mut Self
+    where
+        F: Fn(RequestBuilder) -> RequestBuilder + 'static,
+    {
+        self.interceptors.push(Rc::new(interceptor));
+        self
+    }
+
+    pub fn set_on_token_invalid(&mut self, action: Option<TokenInvalidAction>) -> &mut Self {
+        self.on_token_invalid = action;
+        self
+    }
+
+    fn apply(&self, mut builder: RequestBuilder) -> RequestBuilder {
+        if let Some(base_url) = &self.base_url {
+            if !builder.url.contains("://") && !builder.url.starts_with("//") {
+                builder.url = format!("{}/{}", base_url.trim_end_matches('/'), builder.url.trim_start_matches('/'));
+            }
+        }
+        self.interceptors.iter().fold(builder, |builder, interceptor| interceptor(builder))
+    }
+}
+
+thread_local! {
+    static CLIENT_CONFIG: RefCell<ClientConfig> = RefCell::new(ClientConfig::default());
+    /// Requests waiting for the token refresh in progress
+    static REFRESH_WAITERS: RefCell<Option<Vec<oneshot::Sender<bool>>>> = const { RefCell::new(None) };
+    /// Increased by every successful refresh
+    static TOKEN_GEN: Cell<u64> = const { Cell::new(0) };
+}
+
+pub fn set_client_config(config: ClientConfig) {
+    CLIENT_CONFIG.with(|c| *c.borrow_mut() = config);
+}
+
+pub fn client_config() -> ClientConfig {
+    CLIENT_CONFIG.with(|c| c.borrow().clone())
+}
+
+/// Run `refresh` unless another request is already doing it, then share its result.
+async fn refresh_token(refresh: &RefreshToken) -> bool {
+    let waiter = REFRESH_WAITERS.with(|w| {
+        let mut w = w.borrow_mut();
+        match w.as_mut() {
+            Some(waiters) => {
+                let (sender, receiver) = oneshot::channel();
+                waiters.push(sender);
+                Some(receiver)
+            }
+            None => {
+                *w = Some(Vec::new());
+                None
+            }
+        }
+    });
+    if let Some(receiver) = waiter {
+        return receiver.await.unwrap_or(false);
+    }
+    let mut in_progress = RefreshInProgress { refreshed: false };
+    let refreshed = refresh().await;
+    if refreshed {
+        TOKEN_GEN.with(|g| g.set(g.get() + 1));
+    }
+    in_progress.refreshed = refreshed;
+    refreshed
+}
+
+/// Ends the refresh in progress when dropped, waiters get `refreshed`, also if the request
+/// running the refresh is dropped before it finishes.
+struct RefreshInProgress {
+    refreshed: bool,
+}
+
+impl Drop for RefreshInProgress {
+    fn drop(&mut self) {
+        let waiters = REFRESH_WAITERS.with(|w| w.borrow_mut().take()).unwrap_or_default();
+        for waiter in waiters {
+            let _ = waiter.send(self.refreshed);
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Part {
+    File(web_sys::File),
+    Text(String),
+}
+
+#[derive(Clone)]
+enum Body {
+    Bytes(Vec<u8>),
+    Text(String),
+    Form(FormData),
+    /// Multipart form, as field name and value
+    Multipart(Vec<(String, Part)>),
+    Js(JsValue),
+}
+
+impl Body {
+    fn to_js(&self) -> Result<JsValue, FetchError> {
+        Ok(match self {
+            Body::Bytes(b) => js_sys::Uint8Array::from(b.as_slice()).into(),
+            Body::Text(s) => JsValue::from(s),
+            Body::Form(form) => form.clone().into(),
+            Body::Multipart(parts) => {
+                let form = FormData::new()?;
+                for (name, part) in parts {
+                    match part {
+                        Part::File(file) => form.append_with_blob_and_filename(name, file, &file.name())?,
+                        Part::Text(value) => form.append_with_str(name, value)?,
+                    }
+                }
+                form.into()
+            }
+            Body::Js(v) => v.clone(),
+        })
+    }
+}
+
+type Abort = Rc<RefCell<Option<Box<dyn FnOnce()>>>>;
+
+/// Aborts the request it is given to, the latest one if it is reused.
+#[derive(Clone, Default)]
+pub struct AbortHandle {
+    abort: Abort,
+    /// Set by `abort`, for requests waiting to retry
+    aborted: Rc<Cell<bool>>,
+}
+
+impl PartialEq for AbortHandle {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.abort, &other.abort)
+    }
+}
+
+impl AbortHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The request fails with `FetchError::Aborted`.
+    pub fn abort(&self) {
+        self.aborted.set(true);
+        let abort = self.abort.borrow_mut().take();
+        if let Some(abort) = abort {
+            abort();
+        }
+    }
+
+    pub(crate) fn set<F: FnOnce() + 'static>(&self, abort: F) {
+        self.abort.replace(Some(Box::new(abort)));
+    }
+
+    pub(crate) fn is_aborted(&self) -> bool {
+        self.aborted.get()
+    }
+
+    /// Called when the handle is attached to a new request.
+    pub(crate) fn reset(&self) {
+        self.aborted.set(false);
+    }
+}
+
+thread_local! {
+    /// Requests sent with `cancel_previous`, by key
+    static IN_FLIGHT: RefCell<HashMap<String, AbortController>> = RefCell::new(HashMap::new());
+    /// Number of requests sent with each key, a request waiting to retry gives up when it changes
+    static KEY_SEQ: RefCell<HashMap<String, u64>> = RefCell::new(HashMap::new());
+}
+
+fn key_seq(key: &str, increase: bool) -> u64 {
+    KEY_SEQ.with(|m| {
+        let mut m = m.borrow_mut();
+        let seq = m.entry(key.to_string()).or_default();
+        if increase {
+            *seq += 1;
+        }
+        *seq
+    })
+}
+
+/// Abort the request in flight sent with `cancel_previous(key)`, e.g. when a component is destroyed.
+pub fn cancel_request(key: &str) {
+    key_seq(key, true);
+    if let Some(controller) = IN_FLIGHT.with(|m| m.borrow_mut().remove(key)) {
+        controller.abort();
+    }
+}
+
+/// Abort state of one request, alive until its response is read.
+pub(crate) struct Exchange {
+    controller: AbortController,
+    timed_out: Rc<Cell<bool>>,
+    done: Rc<Cell<bool>>,
+    key: Option<String>,
+}
+
+impl Exchange {
+    /// The error of a failed read, `Timeout` or `Aborted` if the request was stopped.
+    pub(crate) fn error(&self, e: JsValue) -> FetchError {
+        let aborted = e.dyn_ref::<DomException>().is_some_and(|e| e.name() == "AbortError");
+        if self.timed_out.get() {
+            FetchError::Timeout
+        } else if aborted {
+            FetchError::Aborted
+        } else {
+            FetchError::from(e)
+        }
+    }
+}
+
+impl Drop for Exchange {
+    fn drop(&mut self) {
+        self.done.set(true);
+        if let Some(key) = &self.key {
+            IN_FLIGHT.with(|m| {
+                let mut m = m.borrow_mut();
+                let current: Option<&JsValue> = m.get(key).map(|c| c.as_ref());
+                if current == Some(self.controller.as_ref()) {
+                    m.remove(key);
+                }
+            });
+        }
+    }
+}
+
+/// Builds and sends one HTTP request with `fetch`, changed first by the `ClientConfig`.
+///
+/// ```ignore
+/// let points: Vec<u8> = RequestBuilder::get("/api/v1/points")
+///     .query("version", 3)
+///     .headers(&header)
+///     .bytes()
+///     .await?;
+/// ```
+#[derive(Clone)]
+pub struct RequestBuilder {
+    method: Method,
+    url: String,
+    params: Vec<(String, String)>,
+    headers: Vec<(String, String)>,
+    body: Option<Body>,
+    /// Failure while building, returned when sending
+    error: Option<FetchError>,
+    timeout: Option<Duration>,
+    abort: Option<AbortHandle>,
+    key: Option<String>,
+    retry: Option<RetryPolicy>,
+    /// Replaces the default cache policy if set
+    cache: Option<Option<CachePolicy>>,
+    /// Not changed by the `ClientConfig`
+    bare: bool,
+}
+
+impl RequestBuilder {
+    pub fn new<S: Into<String>>(method: Method, url: S) -> Self {
+        Self {
+            method,
+            url: url.into(),
+            params: Vec::new(),
+            headers: Vec::new(),
+            body: None,
+            error: None,
+            timeout: None,
+            abort: None,
+            key: None,
+            retry: None,
+            cache: None,
+            bare: false,
+        }
+    }
+
+    pub fn get<S: Into<String>>(url: S) -> Self {
+        Self::new(Method::Get, url)
+    }
+
+    pub fn head<S: Into<String>>(url: S) -> Self {
+        Self::new(Method::Head, url)
+    }
+
+    pub fn post<S: Into<String>>(url: S) -> Self {
+        Self::new(Method::Post, url)
+    }
+
+    pub fn put<S: Into<String>>(url: S) -> Self {
+        Self::new(Method::Put, url)
+    }
+
+    pub fn patch<S: Into<String>>(url: S) -> Self {
+        Self::new(Method::Patch, url)
+    }
+
+    pub fn delete<S: Into<String>>(url: S) -> Self {
+        Self::new(Method::Delete, url)
+    }
+
+    pub fn method(&self) -> Method {
+        self.method
+    }
+
+    /// Append a query parameter, it is percent-encoded.
+    pub fn query<K: Into<String>, V: ToString>(mut self, key: K, value: V) -> Self {
+        self.params.push((key.into(), value.to_string()));
+        self
+    }
+
+    /// Append query parameters, they are percent-encoded.
+    pub fn queries(mut self, params: HashMap<String, String>) -> Self {
+        self.params.extend(params);
+        self
+    }
+
+    pub fn header<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.headers.push((key.into(), value.into()));
+        self
+    }
+
+    /// Copy every entry of `headers`.
+    pub fn headers(mut self, headers: &Headers) -> Self {
+        if let Ok(Some(entries)) = js_sys::try_iter(headers) {
+            for entry in entries.flatten() {
+                let entry: js_sys::Array = entry.unchecked_into();
+                if let (Some(k), Some(v)) = (entry.get(0).as_string(), entry.get(1).as_string()) {
+                    self.headers.push((k, v));
+                }
+            }
+        }
+        self
+    }
+
+    pub fn json<T: Serialize + ?Sized>(mut self, value: &T) -> Self {
+        match serde_json::to_string(value) {
+            Ok(s) => self.body = Some(Body::Text(s)),
+            Err(e) => self.error = Some(FetchError::Encode(e.to_string())),
+        }
+        self.header("Content-Type", "application/json")
+    }
+
+    pub fn cbor<T: Serialize>(mut self, value: &T) -> Self {
+        match serde_cbor::to_vec(value) {
+            Ok(b) => self.body = Some(Body::Bytes(b)),
+            Err(e) => self.error = Some(FetchError::Encode(e.to_string())),
+        }
+        self.header("Content-Type", "application/cbor")
+    }
+
+    pub fn text<S: Into<String>>(mut self, text: S) -> Self {
+        self.body = Some(Body::Text(text.into()));
+        self
+    }
+
+    pub fn raw(mut self, bytes: Vec<u8>) -> Self {
+        self.body = Some(Body::Bytes(bytes));
+        self
+    }
+
+    pub fn form(mut self, form: FormData) -> Self {
+        self.body = Some(Body::Form(form));
+        self
+    }
+
+    /// Add a file to a multipart body, under the field `name`.
+    pub fn file<S: Into<String>>(self, name: S, file: &web_sys::File) -> Self {
+        self.part(name.into(), Part::File(file.clone()))
+    }
+
+    /// Add a text field to a multipart body.
+    pub fn field<K: Into<String>, V: Into<String>>(self, name: K, value: V) -> Self {
+        self.part(name.into(), Part::Text(value.into()))
+    }
+
+    fn part(mut self, name: String, part: Part) -> Self {
+        match &mut self.body {
+            Some(Body::Multipart(parts)) => parts.push((name, part)),
+            _ => self.body = Some(Body::Multipart(vec![(name, part)])),
+        }
+        self
+    }
+
+    /// Any body accepted by `fetch`.
+    pub fn body(mut self, body: JsValue) -> Self {
+        self.body = Some(Body::Js(body));
+        self
+    }
+
+    /// Send as built, ignoring the `ClientConfig`. Requests refreshing the token must use it.
+    pub fn without_interceptors(mut self) -> Self {
+        self.bare = true;
+        self
+    }
+
+    /// Replace the default retry policy, see `set_default_retry_policy`.
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Replace the default cache policy of GET requests, see `set_default_cache_policy`.
+    /// None to always fetch.
+    pub fn cache(mut self, policy: Option<CachePolicy>) -> Self {
+        self.cache = Some(policy);
+        self
+    }
+
+    /// Fail with `FetchError::Timeout` if the response is not read in time, for each attempt.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn abort_handle(mut self, handle: &AbortHandle) -> Self {
+        handle.reset();
+        self.abort = Some(handle.clone());
+        self
+    }
+
+    /// Abort the previous request sent with the same key if still in flight,
+    /// so a stale response never overwrites a newer one. It fails with `FetchError::Aborted`.
+    pub fn cancel_previous<S: Into<String>>(mut self, key: S) -> Self {
+        self.key = Some(key.into());
+        self
+    }
+
+    /// The url with the query parameters.
+    pub fn url(&self) -> String {
+        if self.params.is_empty() {
+            return self.url.clone();
+        }
+        let query = self
+            .params
+            .iter()
+            .map(|(k, v)| format!("{}={}", encode_component(k), encode_component(v)))
+            .collect::<Vec<_>>()
+            .join("&");
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        format!("{}{}{}", self.url, separator, query)
+    }
+
+    /// The url sent, as changed by the `ClientConfig`. Responses are cached by it, and mutations
+    /// invalidate by it, whatever the transport.
+    pub(crate) fn final_url(&self) -> String {
+        if self.bare {
+            self.url()
+        } else {
+            client_config().apply(self.clone()).url()
+        }
+    }
+
+    fn build(&self, opts: &RequestInit) -> Result<Request, FetchError> {
+        let url = self.url();
+        if let Some(e) = &self.error {
+            return Err(e.clone());
+        }
+        opts.set_method(&self.method.to_string());
+        let headers = Headers::new()?;
+        for (k, v) in &self.headers {
+            headers.append(k, v)?;
+        }
+        opts.set_headers(&headers);
+        if let Some(body) = &self.body {
+            opts.set_body(&body.to_js()?);
+        }
+        Ok(Request::new_with_str_and_init(&url, opts)?)
+    }
+
+    /// The request as changed by the `ClientConfig`, for transports other than `fetch`.
+    pub(crate) fn prepare(self) -> Result<PreparedRequest, FetchError> {
+        let builder = if self.bare { self } else { client_config().apply(self) };
+        if let Some(e) = builder.error {
+            return Err(e);
+        }
+        Ok(PreparedRequest {
+            method: builder.method,
+            url: builder.url(),
+            body: builder.body.as_ref().map(|b| b.to_js()).transpose()?,
+            headers: builder.headers,
+            timeout: builder.timeout,
+            abort: builder.abort,
+        })
+    }
+
+    pub(crate) async fn fetch(mut self) -> Result<(Response, Exchange), FetchError> {
+        // aborted before the request was first polled
+        if self.abort.as_ref().is_some_and(|h| h.is_aborted()) {
+            return Err(FetchError::Aborted);
+        }
+        let policy = self.retry.take().or_else(default_retry_policy);
+        let seq = self.key.as_deref().map(|key| key_seq(key, true));
+        let config = if self.bare { ClientConfig::default() } else { client_config() };
+        let mut attempt = 1;
+        let mut replayed = false;
+        loop {
+            let token_gen = TOKEN_GEN.with(|g| g.get());
+            match self.fetch_once(&config).await {
+                Err(FetchError::TokenInvalid) if !replayed && config.on_token_invalid.is_some() => {
+                    match config.on_token_invalid.as_ref().unwrap() {
+                        TokenInvalidAction::Redirect(url) => {
+                            if let Err(e) = web_sys::window().unwrap().location().set_href(url) {
+                                log::warn!("!!Failed to redirect to {}, {:?}", url, e);
+                            }
+                            return Err(FetchError::TokenInvalid);
+                        }
+                        TokenInvalidAction::Refresh(refresh) => {
+                            // the token may have been refreshed while this request was in flight
+                            let refreshed = TOKEN_GEN.with(|g| g.get()) != token_gen || refresh_token(refresh).await;
+                            if !refreshed {
+                                return Err(FetchError::TokenInvalid);
+                            }
+                            replayed = true;
+                        }
+                    }
+                }
+                Err(e) if policy.as_ref().is_some_and(|p| p.should_retry(self.method, attempt, &e)) => {
+                    let delay = policy.as_ref().unwrap().delay(attempt);
+                    log::debug!("Retry {} {} in {:?} after: {}", self.method, self.url, delay, e);
+                    sleep(delay).await;
+                    if self.given_up(seq) {
+                        return Err(FetchError::Aborted);
+                    }
+                    attempt += 1;
+                }
+                result => {
+                    if result.is_ok() && !matches!(self.method, Method::Get | Method::Head) {
+                        invalidate_cache(&config.apply(self.clone()).url());
+                    }
+                    return result;
+                }
+            }
+        }
+    }
+
+    /// Whether a request waiting to retry was aborted, or replaced by a newer one with the same key.
+    fn given_up(&self, seq: Option<u64>) -> bool {
+        self.abort.as_ref().is_some_and(|h| h.is_aborted())
+            || (seq.is_some() && self.key.as_deref().map(|key| key_seq(key, false)) != seq)
+    }
+
+    async fn fetch_once(&self, config: &ClientConfig) -> Result<(Response, Exchange), FetchError> {
+        let controller = AbortController::new()?;
+        let exchange = Exchange {
+            controller: controller.clone(),
+            timed_out: Rc::new(Cell::new(false)),
+            done: Rc::new(Cell::new(false)),
+            key: self.key.clone(),
+        };
+        if let Some(key) = &exchange.key {
+            let previous = IN_FLIGHT.with(|m| m.borrow_mut().insert(key.clone(), controller.clone()));
+            if let Some(previous) = previous {
+                previous.abort();
+            }
+        }
+        if let Some(handle) = &self.abort {
+            let controller = controller.clone();
+            handle.set(move || controller.abort());
+        }
+        if let Some(timeout) = self.timeout {
+            let timed_out = exchange.timed_out.clone();
+            let done = exchange.done.clone();
+            let controller = controller.clone();
+            spawn_local(async move {
+                sleep(timeout).await;
+                if !done.get() {
+                    timed_out.set(true);
+                    controller.abort();
+                }
+            });
+        }
+        let opts = RequestInit::new();
+        opts.set_signal(Some(&controller.signal()));
+        let request = config.apply(self.clone()).build(&opts)?;
+        let window = web_sys::window().unwrap();
+        let resp_value = JsFuture::from(window.fetch_with_request(&request))
+            .await
+            .map_err(|e| exchange.error(e))?;
+        let resp: Response = resp_value.dyn_into()?;
+        check_resp(&resp).await?;
+        Ok((resp, exchange))
+    }
+
+    /// Send the request, the response is checked by its status and the permission headers.
+    /// The timeout stops once the headers are received, the other methods include reading the body.
+    pub async fn send(self) -> Result<Response, FetchError> {
+        Ok(self.fetch().await?.0)
+    }
+
+    /// For responses without a body.
+    pub async fn ok(self) -> Result<bool, FetchError> {
+        Ok(self.send().await?.ok())
+    }
+
+    pub async fn bytes(self) -> Result<Vec<u8>, FetchError> {
+        Ok(self.read().await?.body)
+    }
+
+    pub async fn string(self) -> Result<String, FetchError> {
+        Ok(String::from_utf8_lossy(&self.read().await?.body).into_owned())
+    }
+
+    pub async fn json_response<T: DeserializeOwned>(self) -> Result<T, FetchError> {
+        let bytes = self.bytes().await?;
+        Format::Json.decode(&bytes)
+    }
+
+    pub async fn cbor_response<T: DeserializeOwned>(self) -> Result<T, FetchError> {
+        let bytes = self.bytes().await?;
+        Format::Cbor.decode(&bytes)
+    }
+
+    /// Deserialize the response with the format given by its `Content-Type`, see `Format::from_content_type`.
+    pub async fn decode<T: DeserializeOwned>(self) -> Result<T, FetchError> {
+        let cached = self.read().await?;
+        Format::from_content_type(cached.content_type.as_deref()).decode(&cached.body)
+    }
+
+    /// The response body, from the cache if the cache policy allows.
+    async fn read(self) -> Result<Cached, FetchError> {
+        let policy = match self.method {
+            Method::Get => self.cache.clone().unwrap_or_else(default_cache_policy),
+            _ => None,
+        };
+        let Some(policy) = policy else {
+            return self.read_response().await;
+        };
+        let url = self.final_url();
+        match cache::lookup(&url, &policy) {
+            Lookup::Fresh(cached) => Ok(cached),
+            Lookup::Stale(cached) => {
+                if cache::start_revalidate(&url) {
+                    spawn_local(async move {
+                        let result = self.read_response().await;
+                        cache::finish_revalidate(&url);
+                        match result {
+                            Ok(fresh) => cache::store(&url, &policy, fresh),
+                            Err(e) => log::debug!("Failed to revalidate {}: {}", url, e),
+                        }
+                    });
+                }
+                Ok(cached)
+            }
+            Lookup::Miss => {
+                let cached = self.read_response().await?;
+                cache::store(&url, &policy, cached.clone());
+                Ok(cached)
+            }
+        }
+    }
+
+    async fn read_response(self) -> Result<Cached, FetchError> {
+        let (resp, exchange) = self.fetch().await?;
+        let content_type = resp.headers().get("Content-Type").ok().flatten();
+        let body = read_bytes(&resp).await.map_err(|e| exchange.error(e))?;
+        Ok(Cached { body, content_type })
+    }
+}
+
+pub(crate) struct PreparedRequest {
+    pub method: Method,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<JsValue>,
+    pub timeout: Option<Duration>,
+    pub abort: Option<AbortHandle>,
+}
+
+/// Serialization format of a response body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Cbor,
+}
+
+impl Format {
+    /// JSON for `application/json` and `+json` types, CBOR for anything else, as our backends send by default.
+    pub fn from_content_type(content_type: Option<&str>) -> Self {
+        let mime = content_type
+            .and_then(|t| t.split(';').next())
+            .map(|t| t.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        if mime == "application/json" || mime.ends_with("+json") {
+            Format::Json
+        } else {
+            Format::Cbor
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, FetchError> {
+        match self {
+            Format::Json => serde_json::from_slice(bytes).map_err(|e| FetchError::Decode(e.to_string())),
+            Format::Cbor => serde_cbor::from_slice(bytes).map_err(|e| FetchError::Decode(e.to_string())),
+        }
+    }
+}
+
+/// Percent-encode everything but the unreserved characters of RFC 3986.
+pub(crate) fn encode_component(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+async fn read_bytes(resp: &Response) -> Result<Vec<u8>, JsValue> {
+    let bytes = JsFuture::from(resp.array_buffer()?).await?;
+    let abuf = bytes.dyn_into::<js_sys::ArrayBuffer>()?;
+    Ok(js_sys::Uint8Array::new(&abuf).to_vec())
+}
+
+fn with_body(builder: RequestBuilder, value: Option<JsValue>) -> RequestBuilder {
+    match value {
+        Some(v) => builder.body(v),
+        None => builder,
+    }
+}
+
+pub async fn async_ws_get_no_header(url: &str) -> Result<Vec<u8>, FetchError> {
+    RequestBuilder::get(url).bytes().await
+}
+
+pub async fn async_ws_get(url: &str, header: &Headers) -> Result<Vec<u8>, FetchError> {
+    RequestBuilder::get(url).headers(header).bytes().await
+}
+
+/// The values of `param` are sent as given, they are not percent-encoded as with
+/// `RequestBuilder::query`.
+pub async fn async_ws_get_with_param(
+    url: &str,
+    header: &Headers,
+    param: Option<HashMap<String, String>>,
+) -> Result<Vec<u8>, FetchError> {
+    RequestBuilder::get(legacy_param_url(url, param))
+        .headers(header)
+        .bytes()
+        .await
+}
+
+fn legacy_param_url(url: &str, param: Option<HashMap<String, String>>) -> String {
+    match param {
+        Some(param) => {
+            let query: Vec<String> = param.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+            format!("{}?{}", url, query.join("&"))
+        }
+        None => url.to_string(),
+    }
+}
+
+pub async fn async_ws_post(url: &str, header: &Headers, value: Option<JsValue>) -> Result<Vec<u8>, FetchError> {
+    with_body(RequestBuilder::post(url), value).headers(header).bytes().await
+}
+
+pub async fn async_ws_post_no_resp(url: &str, header: &Headers, value: Option<JsValue>) -> Result<bool, FetchError> {
+    with_body(RequestBuilder::post(url), value).headers(header).ok().await
+}
+
+pub async fn async_ws_post_file_no_resp(url: &str, header: &Headers, file: &web_sys::File) -> Result<bool, FetchError> {
+    RequestBuilder::post(url).file("file", file).headers(header).ok().await
+}
+
+pub async fn async_ws_post_file(url: &str, header: &Headers, file: &web_sys::File) -> Result<Vec<u8>, FetchError> {
+    RequestBuilder::post(url).file("file", file).headers(header).bytes().await
+}
+
+/// `async_ws_get` deserializing the response by its `Content-Type`.
+pub async fn async_ws_get_as<T: DeserializeOwned>(url: &str, header: &Headers) -> Result<T, FetchError> {
+    RequestBuilder::get(url).headers(header).decode().await
+}
+
+/// See `async_ws_get_with_param`.
+pub async fn async_ws_get_with_param_as<T: DeserializeOwned>(
+    url: &str,
+    header: &Headers,
+    param: Option<HashMap<String, String>>,
+) -> Result<T, FetchError> {
+    RequestBuilder::get(legacy_param_url(url, param))
+        .headers(header)
+        .decode()
+        .await
+}
+
+pub async fn async_ws_post_as<T: DeserializeOwned>(
+    url: &str,
+    header: &Headers,
+    value: Option<JsValue>,
+) -> Result<T, FetchError> {
+    with_body(RequestBuilder::post(url), value).headers(header).decode().await
+}
+
+pub async fn async_ws_put_as<T: DeserializeOwned>(
+    url: &str,
+    header: &Headers,
+    value: Option<JsValue>,
+) -> Result<T, FetchError> {
+    with_body(RequestBuilder::put(url), value).headers(header).decode().await
+}
+
+pub async fn async_ws_put(url: &str, header: &Headers, value: Option<JsValue>) -> Result<Vec<u8>, FetchError> {
+    with_body(RequestBuilder::put(url), value).headers(header).bytes().await
+}
+
+pub async fn async_ws_put_no_resp(url: &str, header: &Headers, value: Option<JsValue>) -> Result<bool, FetchError> {
+    with_body(RequestBuilder::put(url), value).headers(header).ok().await
+}
+
+pub async fn async_ws_delete(url: &str, header: &Headers, value: Option<JsValue>) -> Result<bool, FetchError> {
+    with_body(RequestBuilder::delete(url), value).headers(header).ok().await
+}
+
+/// The error given by the permission headers of a response, read with `get`.
+pub(crate) fn header_error<F: Fn(&str) -> Option<String>>(get: F) -> Option<FetchError> {
+    if get(HEADER_TOKEN_INVALID).is_some_and(|s| s == "true") {
+        return Some(FetchError::TokenInvalid);
+    }
+    if get(HEADER_PERMISSION_DENIED).is_some_and(|s| s == "true") {
+        return Some(FetchError::PermissionDenied);
+    }
+    None
+}
+
+async fn check_resp(resp: &Response) -> Result<bool, FetchError> {
+    let resp_headers = resp.headers();
+    let status = resp.status();
+    if let Some(e) = header_error(|name| resp_headers.get(name).ok().flatten()) {
+        return Err(e);
+    }
+    if status != 200 {
+        let mut body = String::new();
+        if let Ok(promise) = resp.text() {
+            if let Ok(val) = JsFuture::from(promise).await {
+                body = val.as_string().unwrap_or_default();
+            }
+        }
+        return Err(FetchError::HttpStatus { code: status, body });
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url() {
+        assert_eq!(RequestBuilder::get("/api/points").url(), "/api/points");
+        let builder = RequestBuilder::get("/api/points")
+            .query("version", 3)
+            .query("name", "泵 1&2");
+        assert_eq!(builder.url(), "/api/points?version=3&name=%E6%B3%B5%201%262");
+        let builder = RequestBuilder::delete("/api/points?id=1").query("force", true);
+        assert_eq!(builder.url(), "/api/points?id=1&force=true");
+        assert_eq!(builder.method().to_string(), "DELETE");
+    }
+
+    #[test]
+    fn test_abort_during_backoff() {
+        let handle = AbortHandle::new();
+        let builder = RequestBuilder::get("/api/points").abort_handle(&handle);
+        // armed by the failed attempt
+        handle.set(|| {});
+        assert!(!builder.given_up(None));
+        handle.abort();
+        assert!(builder.given_up(None));
+        // a new request with the handle is not aborted
+        let builder = RequestBuilder::get("/api/points").abort_handle(&handle);
+        assert!(!builder.given_up(None));
+
+        let builder = RequestBuilder::get("/api/points").cancel_previous("points");
+        let seq = Some(key_seq("points", true));
+        assert!(!builder.given_up(seq));
+        cancel_request("points");
+        assert!(builder.given_up(seq));
+    }
+
+    #[test]
+    fn test_abort_before_send() {
+        use futures::FutureExt;
+        let handle = AbortHandle::new();
+        let builder = RequestBuilder::get("/api/points").abort_handle(&handle);
+        // e.g. the request queued by `use_fetch` is replaced before it runs
+        handle.abort();
+        assert!(matches!(builder.fetch().now_or_never(), Some(Err(FetchError::Aborted))));
+    }
+
+    #[test]
+    fn test_refresh_dropped() {
+        use futures::FutureExt;
+        let waker = futures::task::noop_waker();
+        let mut cx = std::task::Context::from_waker(&waker);
+        let pending: RefreshToken = Rc::new(|| Box::pin(futures::future::pending()));
+        let mut leader = Box::pin(refresh_token(&pending));
+        assert!(leader.poll_unpin(&mut cx).is_pending());
+        let mut waiter = Box::pin(refresh_token(&pending));
+        assert!(waiter.poll_unpin(&mut cx).is_pending());
+        // e.g. the request running the refresh timed out
+        drop(leader);
+        assert_eq!(waiter.now_or_never(), Some(false));
+        // the next request refreshes again
+        let ready: RefreshToken = Rc::new(|| Box::pin(async { true }));
+        assert_eq!(refresh_token(&ready).now_or_never(), Some(true));
+    }
+
+    #[test]
+    fn test_error_display() {
+        assert_eq!(FetchError::TokenInvalid.to_string(), HEADER_TOKEN_INVALID);
+        assert_eq!(FetchError::PermissionDenied.to_string(), HEADER_PERMISSION_DENIED);
+        let e = FetchError::HttpStatus {
+            code: 500,
+            body: "point not found".to_string(),
+        };
+        assert_eq!(e.to_string(), "point not found");
+        let e = FetchError::HttpStatus {
+            code: 502,
+            body: String::new(),
+        };
+        assert_eq!(e.to_string(), "502");
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let policy = RetryPolicy::default();
+        let unavailable = FetchError::HttpStatus {
+            code: 503,
+            body: String::new(),
+        };
+        let not_found = FetchError::HttpStatus {
+            code: 404,
+            body: String::new(),
+        };
+        let network = FetchError::Network("Failed to fetch".to_string());
+        assert!(policy.should_retry(Method::Get, 1, &unavailable));
+        assert!(policy.should_retry(Method::Delete, 2, &network));
+        assert!(policy.should_retry(Method::Put, 1, &FetchError::Timeout));
+        assert!(!policy.should_retry(Method::Get, 3, &unavailable));
+        assert!(!policy.should_retry(Method::Get, 1, &not_found));
+        assert!(!policy.should_retry(Method::Get, 1, &FetchError::Aborted));
+        assert!(!policy.should_retry(Method::Get, 1, &FetchError::TokenInvalid));
+        assert!(!policy.should_retry(Method::Post, 1, &unavailable));
+        assert!(!policy.should_retry(Method::Patch, 1, &network));
+
+        let mut policy = RetryPolicy::default();
+        policy
+            .set_non_idempotent(true)
+            .set_retry_on_network(false)
+            .set_max_attempts(5)
+            .set_initial_delay(Duration::from_secs(1))
+            .set_max_delay(Duration::from_secs(3));
+        assert!(policy.should_retry(Method::Post, 4, &unavailable));
+        assert!(!policy.should_retry(Method::Post, 1, &network));
+        let delays: Vec<_> = (1..=4).map(|attempt| policy.delay(attempt).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 3, 3]);
+    }
+
+    #[test]
+    fn test_client_config() {
+        let mut config = ClientConfig::default();
+        config
+            .set_base_url("https://plant.example.com/api/")
+            .add_interceptor(|b| b.header("Authorization", "Bearer t1"))
+            .add_interceptor(|b| b.query("lang", "zh"));
+        let builder = config.apply(RequestBuilder::get("/points").query("version", 2));
+        assert_eq!(builder.url(), "https://plant.example.com/api/points?version=2&lang=zh");
+        assert_eq!(builder.headers, vec![("Authorization".to_string(), "Bearer t1".to_string())]);
+        let builder = config.apply(RequestBuilder::get("http://other.example.com/points"));
+        assert_eq!(builder.url(), "http://other.example.com/points?lang=zh");
+
+        // the cache and the transports other than fetch use the same url
+        set_client_config(config);
+        let builder = RequestBuilder::get("/points").query("version", 2);
+        assert_eq!(builder.final_url(), "https://plant.example.com/api/points?version=2&lang=zh");
+        assert_eq!(builder.final_url(), builder.clone().prepare().unwrap().url);
+        set_client_config(ClientConfig::default());
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(Format::from_content_type(Some("application/json")), Format::Json);
+        assert_eq!(Format::from_content_type(Some("Application/JSON; charset=utf-8")), Format::Json);
+        assert_eq!(Format::from_content_type(Some("application/problem+json")), Format::Json);
+        assert_eq!(Format::from_content_type(Some("application/cbor")), Format::Cbor);
+        assert_eq!(Format::from_content_type(None), Format::Cbor);
+
+        let points = vec![(1u64, "p1".to_string())];
+        let json = serde_json::to_vec(&points).unwrap();
+        assert_eq!(Format::Json.decode::<Vec<(u64, String)>>(&json), Ok(points.clone()));
+        let cbor = serde_cbor::to_vec(&points).unwrap();
+        assert_eq!(Format::Cbor.decode::<Vec<(u64, String)>>(&cbor), Ok(points));
+        assert!(matches!(Format::Cbor.decode::<Vec<(u64, String)>>(&json), Err(FetchError::Decode(_))));
+    }
+
+    #[test]
+    fn test_legacy_param_url() {
+        assert_eq!(legacy_param_url("/api/points", None), "/api/points");
+        let param = HashMap::from([("name".to_string(), "a%20b".to_string())]);
+        assert_eq!(legacy_param_url("/api/points", Some(param)), "/api/points?name=a%20b");
+    }
+}
@@ -4,7 +4,7 @@ use web_sys::{File as SysFile, HtmlInputElement};
 use yew::events::Event;
 use yew::prelude::*;
 
-use crate::{Alignment, Size};
+use crate::{Alignment, Delete, Progress, Size};
 
 #[derive(Clone, Debug, Properties, PartialEq)]
 pub struct FileProps {
@@ -56,6 +56,13 @@ pub struct FileProps {
     /// 文件类型限制参数
     #[prop_or_default]
     pub accept: Option<String>,
+    /// Upload progress between 0 and 1 of each file in `files`, a `Progress` bar is shown per file
+    /// while this is not empty. See `UploadProgress::per_file`.
+    #[prop_or_default]
+    pub progress: Vec<f32>,
+    /// Shows a delete button next to the progress bars, called with the index of the file.
+    #[prop_or_default]
+    pub on_cancel: Option<Callback<usize>>,
 }
 
 /// A custom file upload input.
@@ -133,8 +140,33 @@ impl Component for File {
             }
             result
         });
+        let progress = ctx
+            .props()
+            .files
+            .iter()
+            .zip(ctx.props().progress.iter())
+            .enumerate()
+            .map(|(i, (file, value))| {
+                let cancel = ctx.props().on_cancel.as_ref().map(|on_cancel| {
+                    let onclick = on_cancel.reform(move |_| i);
+                    html! {<Delete classes={classes!("is-small")} onclick={onclick} />}
+                });
+                html! {
+                    <div class="level is-mobile mb-1">
+                        <div class="level-left">
+                            <span class="level-item">{file.name()}</span>
+                        </div>
+                        <div class="level-item">
+                            <Progress classes={classes!("is-small", "is-info")} value={*value} />
+                        </div>
+                        <div class="level-right">{cancel}</div>
+                    </div>
+                }
+            })
+            .collect::<Html>();
         html! {
-            <div class={classes} style={style}>
+            <>
+            <div class={classes} style={style.clone()}>
                 <label class={"file-label"}>
                     <input disabled={ctx.props().disabled}
                         value={self.input_value.clone()}
@@ -158,6 +190,10 @@ impl Component for File {
                     {filenames}
                 </label>
             </div>
+            if !ctx.props().progress.is_empty() {
+                <div style={style}>{progress}</div>
+            }
+            </>
         }
     }
 }
@@ -0,0 +1,78 @@
+//! `MyEventBus` for function components.
+//!
+//! ```ignore
+//! const ALARMS: Channel<Alarm> = Channel::new("alarms");
+//!
+//! #[function_component]
+//! fn Alarms() -> Html {
+//!     let count = use_state(|| 0);
+//!     let send = {
+//!         let count = count.clone();
+//!         use_channel(&ALARMS, move |_| count.set(*count + 1))
+//!     };
+//!     let onclick = send.reform(|_| Alarm { point: 3, level: 2 });
+//!     html! { <button {onclick}>{ format!("{} alarms", *count) }</button> }
+//! }
+//! ```
+
+use std::rc::Rc;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use yew::prelude::*;
+
+use crate::bus::{Channel, MyEventBus};
+use crate::MyMsg;
+
+/// `use_bus_scoped` receiving every message.
+#[hook]
+pub fn use_bus<F>(on_message: F) -> Callback<MyMsg>
+where
+    F: Fn(MyMsg) + 'static,
+{
+    use_bus_scoped(None, on_message)
+}
+
+/// Call `on_message` with the messages of `scopes`, or with all of them if None, from the first
+/// effect of the component until it is destroyed. The `on_message` of the latest render is
+/// called, so it can use the state handles of that render. Returns a callback sending to the bus.
+#[hook]
+pub fn use_bus_scoped<F>(scopes: Option<Vec<String>>, on_message: F) -> Callback<MyMsg>
+where
+    F: Fn(MyMsg) + 'static,
+{
+    let latest = use_mut_ref(|| None::<Rc<dyn Fn(MyMsg)>>);
+    *latest.borrow_mut() = Some(Rc::new(on_message));
+    use_effect_with(scopes, move |scopes| {
+        let callback = Rc::new(move |msg: MyMsg| {
+            let on_message = latest.borrow().clone();
+            if let Some(on_message) = on_message {
+                on_message(msg);
+            }
+        });
+        let bridge = match scopes.clone() {
+            Some(scopes) => MyEventBus::bridge_scoped(scopes, callback),
+            None => MyEventBus::bridge(callback),
+        };
+        move || drop(bridge)
+    });
+    use_callback((), |msg, _| MyEventBus::send(msg))
+}
+
+/// Call `on_message` with the values sent on `channel`, see `use_bus_scoped`.
+/// Returns a callback sending values on it.
+#[hook]
+pub fn use_channel<T, F>(channel: &Channel<T>, on_message: F) -> Callback<T>
+where
+    T: Serialize + DeserializeOwned + 'static,
+    F: Fn(T) + 'static,
+{
+    let name = channel.name().to_string();
+    let reader = channel.clone();
+    use_bus_scoped(Some(vec![name.clone()]), move |msg| {
+        if let Some(value) = reader.read(&msg) {
+            on_message(value);
+        }
+    });
+    use_callback(name, |value: T, name| Channel::<T>::named(name.clone()).send(&value))
+}
@@ -0,0 +1,349 @@
+//! Requests made by function components, with their loading, error and data state.
+//!
+//! ```ignore
+//! #[function_component]
+//! fn Points(props: &Props) -> Html {
+//!     let points = use_fetch(props.version, |version| {
+//!         RequestBuilder::get("/api/v1/points").query("version", version)
+//!     });
+//!     let delete = use_mutation(|id: u64| async move {
+//!         RequestBuilder::delete(format!("/api/v1/points/{}", id)).ok().await
+//!     });
+//!     if points.loading {
+//!         return html! { <progress class="progress" /> };
+//!     }
+//!     ...
+//! }
+//! ```
+
+use std::cell::Cell;
+use std::future::Future;
+use std::ops::Deref;
+use std::rc::Rc;
+
+use serde::de::DeserializeOwned;
+use yew::platform::spawn_local;
+use yew::prelude::*;
+
+use crate::components::myloading::LoadingMsg;
+use crate::fetch::{AbortHandle, FetchError, RequestBuilder};
+use crate::{MyEventBus, MyMsg};
+
+/// State of the latest request of a `use_fetch` or `use_mutation` hook.
+#[derive(Debug)]
+pub struct FetchState<T> {
+    pub loading: bool,
+    /// Error of the latest request
+    pub error: Option<FetchError>,
+    /// Data of the latest successful request, kept while fetching again and after an error
+    pub data: Option<Rc<T>>,
+}
+
+impl<T> Default for FetchState<T> {
+    fn default() -> Self {
+        Self {
+            loading: false,
+            error: None,
+            data: None,
+        }
+    }
+}
+
+impl<T> Clone for FetchState<T> {
+    fn clone(&self) -> Self {
+        Self {
+            loading: self.loading,
+            error: self.error.clone(),
+            data: self.data.clone(),
+        }
+    }
+}
+
+impl<T> PartialEq for FetchState<T> {
+    fn eq(&self, other: &Self) -> bool {
+        let same_data = match (&self.data, &other.data) {
+            (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+            (a, b) => a.is_none() && b.is_none(),
+        };
+        self.loading == other.loading && self.error == other.error && same_data
+    }
+}
+
+impl<T> FetchState<T> {
+    fn finish(&self, result: Result<T, FetchError>) -> Self {
+        match result {
+            Ok(data) => Self {
+                loading: false,
+                error: None,
+                data: Some(Rc::new(data)),
+            },
+            Err(e) => Self {
+                loading: false,
+                error: Some(e),
+                data: self.data.clone(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FetchOptions {
+    /// Show `MyLoading` while the request is in flight, through `MyMsg::Loading`
+    pub show_loading: bool,
+}
+
+thread_local! {
+    /// Requests showing `MyLoading`, it is hidden once all are over
+    static LOADING: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Shows `MyLoading` until dropped.
+struct LoadingGuard;
+
+impl LoadingGuard {
+    fn start(show: bool) -> Option<Self> {
+        if !show {
+            return None;
+        }
+        if LOADING.with(|l| l.replace(l.get() + 1)) == 0 {
+            MyEventBus::send(MyMsg::Loading(LoadingMsg::Show));
+        }
+        Some(LoadingGuard)
+    }
+}
+
+impl Drop for LoadingGuard {
+    fn drop(&mut self) {
+        if LOADING.with(|l| l.replace(l.get() - 1)) == 1 {
+            MyEventBus::send(MyMsg::Loading(LoadingMsg::Hide));
+        }
+    }
+}
+
+/// Requests of one hook, only the latest one updates the state.
+#[derive(Default)]
+struct Latest {
+    generation: u64,
+    abort: AbortHandle,
+}
+
+impl Latest {
+    /// Aborts the previous request.
+    fn next(&mut self) -> (u64, AbortHandle) {
+        self.abort.abort();
+        self.generation += 1;
+        self.abort = AbortHandle::new();
+        (self.generation, self.abort.clone())
+    }
+}
+
+/// Returned by `use_fetch`, derefs to the `FetchState`.
+pub struct UseFetchHandle<T> {
+    state: UseStateHandle<FetchState<T>>,
+    refetch: Callback<()>,
+}
+
+impl<T> UseFetchHandle<T> {
+    /// Send the request again, with the current dependencies.
+    pub fn refetch(&self) {
+        self.refetch.emit(());
+    }
+
+    pub fn refetch_callback(&self) -> Callback<()> {
+        self.refetch.clone()
+    }
+}
+
+impl<T> Deref for UseFetchHandle<T> {
+    type Target = FetchState<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.state
+    }
+}
+
+impl<T> Clone for UseFetchHandle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            refetch: self.refetch.clone(),
+        }
+    }
+}
+
+/// `use_fetch_with_options` with the default options.
+#[hook]
+pub fn use_fetch<T, D, F>(deps: D, request: F) -> UseFetchHandle<T>
+where
+    T: DeserializeOwned + 'static,
+    D: Clone + PartialEq + 'static,
+    F: Fn(&D) -> RequestBuilder + 'static,
+{
+    use_fetch_with_options(deps, FetchOptions::default(), request)
+}
+
+/// Send the request built from `deps` on mount and whenever `deps` change, and decode its
+/// response with `RequestBuilder::decode`. A request still in flight is aborted by the next
+/// one and when the component is destroyed.
+#[hook]
+pub fn use_fetch_with_options<T, D, F>(deps: D, options: FetchOptions, request: F) -> UseFetchHandle<T>
+where
+    T: DeserializeOwned + 'static,
+    D: Clone + PartialEq + 'static,
+    F: Fn(&D) -> RequestBuilder + 'static,
+{
+    let state = use_state(FetchState::default);
+    let latest = use_mut_ref(Latest::default);
+    // the request of the latest render, for `refetch`
+    let send = use_mut_ref(|| None::<Rc<dyn Fn()>>);
+    *send.borrow_mut() = Some({
+        let state = state.clone();
+        let latest = latest.clone();
+        let deps = deps.clone();
+        Rc::new(move || {
+            let (generation, abort) = latest.borrow_mut().next();
+            // attached before spawning, so an abort before the future runs is not lost
+            let builder = request(&deps).abort_handle(&abort);
+            let previous = (*state).clone();
+            state.set(FetchState {
+                loading: true,
+                ..previous.clone()
+            });
+            let state = state.clone();
+            let latest = latest.clone();
+            let show_loading = options.show_loading;
+            spawn_local(async move {
+                let _loading = LoadingGuard::start(show_loading);
+                let result = builder.decode::<T>().await;
+                if latest.borrow().generation == generation {
+                    state.set(previous.finish(result));
+                }
+            });
+        })
+    });
+    {
+        let send = send.clone();
+        use_effect_with(deps, move |_| {
+            let send = send.borrow().clone();
+            if let Some(send) = send {
+                send();
+            }
+        });
+    }
+    {
+        let latest = latest.clone();
+        use_effect_with((), move |_| move || latest.borrow().abort.abort());
+    }
+    let refetch = use_callback((), move |_, _| {
+        let send = send.borrow().clone();
+        if let Some(send) = send {
+            send();
+        }
+    });
+    UseFetchHandle { state, refetch }
+}
+
+/// Returned by `use_mutation`, derefs to the `FetchState` of the latest run.
+pub struct UseMutationHandle<I, T> {
+    state: UseStateHandle<FetchState<T>>,
+    run: Callback<I>,
+}
+
+impl<I, T> UseMutationHandle<I, T> {
+    pub fn run(&self, input: I) {
+        self.run.emit(input);
+    }
+
+    /// E.g. for the `onclick` of a button, with `Callback::reform`.
+    pub fn run_callback(&self) -> Callback<I> {
+        self.run.clone()
+    }
+}
+
+impl<I, T> Deref for UseMutationHandle<I, T> {
+    type Target = FetchState<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.state
+    }
+}
+
+impl<I, T> Clone for UseMutationHandle<I, T> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            run: self.run.clone(),
+        }
+    }
+}
+
+/// `use_mutation_with_options` with the default options.
+#[hook]
+pub fn use_mutation<I, T, F, Fut>(mutate: F) -> UseMutationHandle<I, T>
+where
+    I: 'static,
+    T: 'static,
+    F: Fn(I) -> Fut + 'static,
+    Fut: Future<Output = Result<T, FetchError>> + 'static,
+{
+    use_mutation_with_options(FetchOptions::default(), mutate)
+}
+
+/// Run `mutate` when asked, e.g. to send a form. Nothing is sent on mount, and runs
+/// are not aborted by the next one, but only the latest one updates the state.
+#[hook]
+pub fn use_mutation_with_options<I, T, F, Fut>(options: FetchOptions, mutate: F) -> UseMutationHandle<I, T>
+where
+    I: 'static,
+    T: 'static,
+    F: Fn(I) -> Fut + 'static,
+    Fut: Future<Output = Result<T, FetchError>> + 'static,
+{
+    let state = use_state(FetchState::default);
+    let generation = use_mut_ref(|| 0u64);
+    let run = {
+        let state = state.clone();
+        Callback::from(move |input: I| {
+            let future = mutate(input);
+            let this = {
+                let mut generation = generation.borrow_mut();
+                *generation += 1;
+                *generation
+            };
+            let previous = (*state).clone();
+            state.set(FetchState {
+                loading: true,
+                ..previous.clone()
+            });
+            let state = state.clone();
+            let generation = generation.clone();
+            let show_loading = options.show_loading;
+            spawn_local(async move {
+                let _loading = LoadingGuard::start(show_loading);
+                let result = future.await;
+                if *generation.borrow() == this {
+                    state.set(previous.finish(result));
+                }
+            });
+        })
+    };
+    UseMutationHandle { state, run }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state() {
+        let state = FetchState::<u32>::default();
+        let done = state.finish(Ok(3));
+        assert_eq!(done.data.as_deref(), Some(&3));
+        assert_eq!(done.clone(), done);
+        let failed = done.finish(Err(FetchError::Timeout));
+        assert_eq!(failed.error, Some(FetchError::Timeout));
+        // the data of the last success is kept
+        assert!(Rc::ptr_eq(failed.data.as_ref().unwrap(), done.data.as_ref().unwrap()));
+        assert_ne!(failed.finish(Ok(3)), done);
+    }
+}
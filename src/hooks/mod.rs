@@ -0,0 +1,7 @@
+mod bus;
+mod fetch;
+pub mod mqtt;
+
+pub use bus::*;
+pub use fetch::*;
+pub use mqtt::*;
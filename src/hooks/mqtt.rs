@@ -0,0 +1,260 @@
+//! A `MySocket` shared by function components through `MqttProvider`.
+//!
+//! ```ignore
+//! #[function_component]
+//! fn Measure() -> Html {
+//!     match use_mqtt_topic::<f64>("plant/+/measure") {
+//!         Some(msg) => html! { <p>{ format!("{}: {}", msg.topic, msg.value) }</p> },
+//!         None => html! {},
+//!     }
+//! }
+//! ```
+
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use yew::prelude::*;
+
+use crate::mqtt::MqttOptions;
+use crate::publish::QoS;
+use crate::socket::{ConnectionState, MqttCallbacks, MySocket, ReconnectPolicy};
+use crate::topic::TopicRouter;
+
+struct MqttHub {
+    socket: RefCell<Option<MySocket>>,
+    listeners: RefCell<TopicRouter<Callback<(String, Bytes)>>>,
+    qos: QoS,
+}
+
+impl MqttHub {
+    fn dispatch(&self, topic: String, payload: Bytes) {
+        let callbacks: Vec<_> = self.listeners.borrow().route(&topic).into_iter().cloned().collect();
+        for callback in callbacks {
+            callback.emit((topic.clone(), payload.clone()));
+        }
+    }
+
+    /// Subscribe to the filters of listeners added while disconnected.
+    fn subscribe_missing(&self) {
+        let socket = self.socket.borrow();
+        let Some(socket) = socket.as_ref() else {
+            return;
+        };
+        let subscribed = socket.subscriptions();
+        let filters = self.listeners.borrow().filters();
+        for filter in filters.iter().filter(|f| !subscribed.contains(f)) {
+            socket.subscribe(filter, self.qos);
+        }
+    }
+}
+
+/// Handle to the socket of the nearest `MqttProvider`.
+#[derive(Clone)]
+pub struct MqttContext {
+    hub: Rc<MqttHub>,
+}
+
+impl PartialEq for MqttContext {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.hub, &other.hub)
+    }
+}
+
+impl MqttContext {
+    pub fn is_connected(&self) -> bool {
+        self.hub.socket.borrow().as_ref().is_some_and(|s| s.is_connected())
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.hub.socket.borrow().as_ref().map_or(ConnectionState::Closed, |s| s.state())
+    }
+
+    pub fn send<T: Serialize>(&self, topic: &str, value: &T) -> bool {
+        self.hub.socket.borrow().as_ref().is_some_and(|s| s.send(topic, value))
+    }
+
+    /// See `MySocket::publish`.
+    pub fn publish<T: Serialize>(&self, topic: &str, value: &T, qos: QoS) -> Option<u16> {
+        self.hub.socket.borrow().as_ref().and_then(|s| s.publish(topic, value, qos))
+    }
+
+    /// Call `callback` with the topic and decoded value of messages matching `filter`
+    /// until the returned id is given to `remove_listener`, None if the filter is invalid.
+    pub fn add_listener<T, F>(&self, filter: &str, callback: F) -> Option<usize>
+    where
+        T: DeserializeOwned,
+        F: Fn(String, T) + 'static,
+    {
+        let weak: Weak<MqttHub> = Rc::downgrade(&self.hub);
+        let callback = Callback::from(move |(topic, payload): (String, Bytes)| {
+            let Some(hub) = weak.upgrade() else {
+                return;
+            };
+            let value = match hub.socket.borrow().as_ref().map(|s| s.decode::<T>(&topic, &payload)) {
+                Some(Ok(value)) => value,
+                Some(Err(e)) => {
+                    log::warn!("!!Failed to decode message of {}, {}", topic, e);
+                    return;
+                }
+                None => return,
+            };
+            callback(topic, value);
+        });
+        let first = !self.hub.listeners.borrow().contains_filter(filter);
+        let Some(id) = self.hub.listeners.borrow_mut().insert(filter, callback) else {
+            log::warn!("!!Invalid topic filter: {}", filter);
+            return None;
+        };
+        if first && self.is_connected() {
+            if let Some(s) = self.hub.socket.borrow().as_ref() {
+                s.subscribe(filter, self.hub.qos);
+            }
+        }
+        Some(id)
+    }
+
+    /// The filter is unsubscribed when its last listener is removed.
+    pub fn remove_listener(&self, id: usize) {
+        let filter = {
+            let mut listeners = self.hub.listeners.borrow_mut();
+            let Some((filter, _)) = listeners.remove(id) else {
+                return;
+            };
+            if listeners.contains_filter(&filter) {
+                return;
+            }
+            filter
+        };
+        if self.is_connected() {
+            if let Some(s) = self.hub.socket.borrow().as_ref() {
+                s.unsubscribe(&filter);
+            }
+        }
+    }
+}
+
+#[derive(Properties, PartialEq)]
+pub struct MqttProviderProps {
+    pub url: AttrValue,
+    #[prop_or_default]
+    pub backend: AttrValue,
+    pub options: MqttOptions,
+    /// QoS of the subscriptions made for `use_mqtt_topic`
+    #[prop_or(QoS::AtMostOnce)]
+    pub qos: QoS,
+    #[prop_or(Some(ReconnectPolicy::default()))]
+    pub reconnect: Option<ReconnectPolicy>,
+    #[prop_or_default]
+    pub on_state: Callback<ConnectionState>,
+    #[prop_or_default]
+    pub children: Children,
+}
+
+/// Owns a `MySocket` speaking MQTT, connected while the provider is mounted.
+#[function_component]
+pub fn MqttProvider(props: &MqttProviderProps) -> Html {
+    let deps = (props.url.clone(), props.backend.clone(), props.options.clone(), props.qos);
+    let context = use_memo(deps, |(url, backend, options, qos)| {
+        let hub = Rc::new_cyclic(|weak: &Weak<MqttHub>| {
+            let on_connect = {
+                let weak = weak.clone();
+                Callback::from(move |_| {
+                    if let Some(hub) = weak.upgrade() {
+                        hub.subscribe_missing();
+                    }
+                })
+            };
+            let on_message = {
+                let weak = weak.clone();
+                Callback::from(move |(topic, payload)| {
+                    if let Some(hub) = weak.upgrade() {
+                        hub.dispatch(topic, payload);
+                    }
+                })
+            };
+            let callbacks = MqttCallbacks {
+                on_connect,
+                on_message,
+                ..Default::default()
+            };
+            let socket = MySocket::new_mqtt(url.to_string(), backend.to_string(), options.clone(), callbacks);
+            MqttHub {
+                socket: RefCell::new(Some(socket)),
+                listeners: RefCell::new(TopicRouter::new()),
+                qos: *qos,
+            }
+        });
+        MqttContext { hub }
+    });
+    {
+        // before connecting, and again whenever they change without replacing the socket
+        let deps = ((*context).clone(), props.reconnect.clone(), props.on_state.clone());
+        use_effect_with(deps, |(context, reconnect, on_state)| {
+            if let Some(socket) = context.hub.socket.borrow_mut().as_mut() {
+                socket.set_reconnect_policy(reconnect.clone());
+                socket.set_on_state(on_state.clone());
+            }
+        });
+    }
+    {
+        let context = (*context).clone();
+        use_effect_with(context, move |context| {
+            if let Some(socket) = context.hub.socket.borrow_mut().as_mut() {
+                socket.connect();
+            }
+            let hub = context.hub.clone();
+            move || {
+                if let Some(mut socket) = hub.socket.borrow_mut().take() {
+                    socket.close();
+                }
+            }
+        });
+    }
+    html! {
+        <ContextProvider<MqttContext> context={(*context).clone()}>
+            { for props.children.iter() }
+        </ContextProvider<MqttContext>>
+    }
+}
+
+/// The socket of the nearest `MqttProvider`, panics without one.
+#[hook]
+pub fn use_mqtt() -> MqttContext {
+    use_context::<MqttContext>().expect("use_mqtt must be used inside MqttProvider")
+}
+
+/// A message received on a topic matching the filter of `use_mqtt_topic`.
+#[derive(Debug, PartialEq)]
+pub struct TopicMessage<T> {
+    pub topic: String,
+    pub value: T,
+}
+
+/// The latest message matching `filter`, decoded with the codec of its topic.
+/// The component renders again on every new message.
+#[hook]
+pub fn use_mqtt_topic<T>(filter: &str) -> Option<Rc<TopicMessage<T>>>
+where
+    T: DeserializeOwned + 'static,
+{
+    let context = use_mqtt();
+    let message = use_state(|| None);
+    {
+        let message = message.clone();
+        use_effect_with((context, filter.to_string()), move |(context, filter)| {
+            let id = context.add_listener(filter, move |topic, value: T| {
+                message.set(Some(Rc::new(TopicMessage { topic, value })));
+            });
+            let context = context.clone();
+            move || {
+                if let Some(id) = id {
+                    context.remove_listener(id);
+                }
+            }
+        });
+    }
+    (*message).clone()
+}
@@ -2,24 +2,18 @@ extern crate core;
 
 use std::borrow::Cow;
 use std::collections::HashMap;
-use std::collections::HashSet;
-use std::error::Error;
 use std::fmt::Debug;
-use std::io;
 
-use bytes::BytesMut;
 use derive_more::Display;
 use gloo_utils::{document, window};
 use serde::{Deserialize, Serialize};
-use js_sys::Function;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
-use wasm_bindgen_futures::JsFuture;
-use web_sys::{CloseEvent, ErrorEvent, FormData, Headers, HtmlInputElement, HtmlSelectElement,
-              HtmlTextAreaElement, MessageEvent, Request, RequestInit, Response, WebSocket};
+use web_sys::{HtmlInputElement, HtmlSelectElement, HtmlTextAreaElement};
 use yew::html::IntoPropValue;
 use yew::prelude::*;
-use yew_agent::{HandlerId, Public, WorkerLink};
+
+use components::dialog::Dialog;
 
 pub use columns::*;
 pub use components::breadcrumb::*;
@@ -60,18 +54,36 @@ pub use layout::level::*;
 pub use layout::media::*;
 pub use layout::section::*;
 pub use layout::tile::*;
-use publish::{Publish, QoS};
-
+pub use bus::*;
+pub use cache::*;
+pub use download::*;
+pub use fetch::*;
+pub use hooks::*;
+pub use socket::*;
+pub use sse::*;
+pub use upload::*;
+
+mod bus;
+mod cache;
 #[cfg(feature = "calendar")]
 pub mod calendar;
 #[cfg(feature = "chart")]
 pub mod chart;
+pub mod codec;
 pub mod columns;
 pub mod components;
+mod download;
 pub mod elements;
+mod fetch;
 pub mod form;
+pub mod hooks;
 pub mod layout;
-mod publish;
+pub mod mqtt;
+pub mod publish;
+mod socket;
+mod sse;
+pub mod topic;
+mod upload;
 
 pub const HEADER_TOKEN_INVALID: &str = "token-invalid";
 pub const HEADER_PERMISSION_DENIED: &str = "permission-denied";
@@ -79,10 +91,6 @@ pub const HEADER_PERMISSION_DENIED: &str = "permission-denied";
 #[wasm_bindgen]
 extern "C" {
     pub fn alert(s: &str);
-    #[wasm_bindgen(js_namespace = ["window"])]
-    fn my_alert_js(msg: &str, callback: &Function);
-    #[wasm_bindgen(js_namespace = ["window"])]
-    fn my_confirm_js(msg: &str, callback: &Function);
 }
 
 /// Common alignment classes.
@@ -111,259 +119,12 @@ pub enum Size {
     Large,
 }
 
-/// Something wrong has occurred while fetching an external resource.
-#[derive(Debug, Clone, PartialEq, Display)]
-#[display("{}", err.as_string().unwrap_or_default())]
-pub struct FetchError {
-    err: JsValue,
-}
-
-impl Error for FetchError {}
-
-impl From<JsValue> for FetchError {
-    fn from(value: JsValue) -> Self {
-        Self { err: value }
-    }
-}
-
 impl IntoPropValue<Option<Cow<'static, str>>> for Size {
     fn into_prop_value(self) -> Option<Cow<'static, str>> {
         Some(Cow::from(self.to_string()))
     }
 }
 
-pub async fn async_ws_get_no_header(url: &str) -> Result<Vec<u8>, FetchError> {
-    let opts = RequestInit::new();
-    opts.set_method("GET");
-    // opts.mode(RequestMode::NoCors);
-    // opts.headers(header);
-
-    let request = Request::new_with_str_and_init(url, &opts)?;
-    let window = web_sys::window().unwrap();
-    let resp_value = JsFuture::from(window.fetch_with_request(&request)).await?;
-    let resp: Response = resp_value.dyn_into()?;
-    check_resp(&resp).await?;
-    let bytes = JsFuture::from(resp.array_buffer()?).await?;
-    let abuf = bytes.dyn_into::<js_sys::ArrayBuffer>()?;
-    let array = js_sys::Uint8Array::new(&abuf);
-    let vec: Vec<u8> = array.to_vec();
-    Ok(vec)
-}
-
-pub async fn async_ws_get(url: &str, header: &Headers) -> Result<Vec<u8>, FetchError> {
-    let opts = RequestInit::new();
-    opts.set_method("GET");
-    // opts.mode(RequestMode::NoCors);
-    opts.set_headers(header);
-
-    let request = Request::new_with_str_and_init(url, &opts)?;
-    let window = web_sys::window().unwrap();
-    let resp_value = JsFuture::from(window.fetch_with_request(&request)).await?;
-    let resp: Response = resp_value.dyn_into()?;
-    check_resp(&resp).await?;
-    let bytes = JsFuture::from(resp.array_buffer()?).await?;
-    let abuf = bytes.dyn_into::<js_sys::ArrayBuffer>()?;
-    let array = js_sys::Uint8Array::new(&abuf);
-    let vec: Vec<u8> = array.to_vec();
-    Ok(vec)
-}
-
-pub async fn async_ws_get_with_param(
-    url: &str,
-    header: &Headers,
-    param: Option<HashMap<String, String>>,
-) -> Result<Vec<u8>, FetchError> {
-    let new_url = if let Some(p) = param {
-        let param_vec = p
-            .iter()
-            .map(|(k, v)| format!("{}={}", k, v))
-            .collect::<Vec<String>>();
-        let param_str = param_vec.join("&");
-        format!("{}?{}", url, param_str)
-    } else {
-        url.to_string()
-    };
-    async_ws_get(&new_url, header).await
-}
-
-pub async fn async_ws_post(url: &str, header: &Headers, value: Option<JsValue>) -> Result<Vec<u8>, FetchError> {
-    let opts = RequestInit::new();
-    opts.set_method("POST");
-    // opts.mode(RequestMode::NoCors);
-    if let Some(v) = value {
-        opts.set_body(&v);
-    }
-    opts.set_headers(header);
-    let request = Request::new_with_str_and_init(url, &opts)?;
-    let window = web_sys::window().unwrap();
-    let resp_value = JsFuture::from(window.fetch_with_request(&request)).await?;
-    let resp: Response = resp_value.dyn_into()?;
-    check_resp(&resp).await?;
-    let bytes: JsValue = JsFuture::from(resp.array_buffer()?).await?;
-    let abuf = bytes.dyn_into::<js_sys::ArrayBuffer>()?;
-    let array = js_sys::Uint8Array::new(&abuf);
-    let vec: Vec<u8> = array.to_vec();
-    Ok(vec)
-}
-
-pub async fn async_ws_post_no_resp(url: &str, header: &Headers, value: Option<JsValue>) -> Result<bool, FetchError> {
-    let opts = RequestInit::new();
-    opts.set_method("POST");
-    // opts.mode(RequestMode::NoCors);
-    if let Some(v) = value {
-        opts.set_body(&v);
-    }
-    opts.set_headers(header);
-    let request = Request::new_with_str_and_init(url, &opts)?;
-    let window = web_sys::window().unwrap();
-    let resp_value = JsFuture::from(window.fetch_with_request(&request)).await?;
-    let resp: Response = resp_value.dyn_into()?;
-    check_resp(&resp).await?;
-    Ok(resp.ok())
-}
-
-pub async fn async_ws_post_file_no_resp(url: &str, header: &Headers, file: &web_sys::File) -> Result<bool, FetchError> {
-    let opts = RequestInit::new();
-    opts.set_method("POST");
-
-    let form_data = FormData::new()?;
-    form_data.append_with_blob("file", file)?;
-    opts.set_body(&JsValue::from(form_data));
-    opts.set_headers(header);
-
-    let request = Request::new_with_str_and_init(url, &opts)?;
-    let window = web_sys::window().unwrap();
-    let resp_value = JsFuture::from(window.fetch_with_request(&request)).await?;
-    let resp: Response = resp_value.dyn_into()?;
-    check_resp(&resp).await?;
-    Ok(resp.ok())
-}
-
-
-pub async fn async_ws_post_file(url: &str, header: &Headers, file: &web_sys::File) -> Result<Vec<u8>, FetchError> {
-    let opts = RequestInit::new();
-    opts.set_method("POST");
-
-    let form_data = FormData::new()?;
-    form_data.append_with_blob("file", file)?;
-    opts.set_body(&JsValue::from(form_data));
-    opts.set_headers(header);
-
-    let request = Request::new_with_str_and_init(url, &opts)?;
-    let window = web_sys::window().unwrap();
-    let resp_value = JsFuture::from(window.fetch_with_request(&request)).await?;
-    let resp: Response = resp_value.dyn_into()?;
-    check_resp(&resp).await?;
-    let bytes = JsFuture::from(resp.array_buffer()?).await?;
-    let abuf = bytes.dyn_into::<js_sys::ArrayBuffer>()?;
-    let array = js_sys::Uint8Array::new(&abuf);
-    let vec: Vec<u8> = array.to_vec();
-    Ok(vec)
-}
-
-
-pub async fn async_ws_put(url: &str, header: &Headers, value: Option<JsValue>) -> Result<Vec<u8>, FetchError> {
-    let opts = RequestInit::new();
-    opts.set_method("PUT");
-    // opts.mode(RequestMode::NoCors);
-    if let Some(v) = value {
-        opts.set_body(&v);
-    }
-    opts.set_headers(header);
-    let request = Request::new_with_str_and_init(url, &opts)?;
-    let window = web_sys::window().unwrap();
-    let resp_value = JsFuture::from(window.fetch_with_request(&request)).await?;
-    let resp: Response = resp_value.dyn_into()?;
-    check_resp(&resp).await?;
-    let bytes = JsFuture::from(resp.array_buffer()?).await?;
-    let abuf = bytes.dyn_into::<js_sys::ArrayBuffer>()?;
-    let array = js_sys::Uint8Array::new(&abuf);
-    let vec: Vec<u8> = array.to_vec();
-    Ok(vec)
-}
-
-pub async fn async_ws_put_no_resp(url: &str, header: &Headers, value: Option<JsValue>) -> Result<bool, FetchError> {
-    let opts = RequestInit::new();
-    opts.set_method("PUT");
-    // opts.mode(RequestMode::NoCors);
-    if let Some(v) = value {
-        opts.set_body(&v);
-    }
-    opts.set_headers(header);
-    let request = Request::new_with_str_and_init(url, &opts)?;
-    let window = web_sys::window().unwrap();
-    let resp_value = JsFuture::from(window.fetch_with_request(&request)).await?;
-    let resp: Response = resp_value.dyn_into()?;
-    check_resp(&resp).await?;
-    Ok(resp.ok())
-}
-
-pub async fn async_ws_delete(url: &str, header: &Headers, value: Option<JsValue>) -> Result<bool, FetchError> {
-    let opts = RequestInit::new();
-    opts.set_method("DELETE");
-    // opts.mode(RequestMode::NoCors);
-    if let Some(v) = value {
-        opts.set_body(&v);
-    }
-    opts.set_headers(header);
-    let request = Request::new_with_str_and_init(url, &opts)?;
-    let window = web_sys::window().unwrap();
-    let resp_value = JsFuture::from(window.fetch_with_request(&request)).await?;
-    let resp: Response = resp_value.dyn_into()?;
-    check_resp(&resp).await?;
-    Ok(resp.ok())
-}
-
-async fn check_resp(resp: &Response) -> Result<bool, FetchError> {
-    let resp_headers = resp.headers();
-    let status = resp.status();
-    let token_invalid = if let Ok(Some(s)) = resp_headers.get(HEADER_TOKEN_INVALID) {
-        s == "true"
-    } else {
-        false
-    };
-    if token_invalid {
-        return Err(FetchError::from(JsValue::from(HEADER_TOKEN_INVALID)));
-    }
-    let permission_denied = if let Ok(Some(s)) = resp_headers.get(HEADER_PERMISSION_DENIED) {
-        s == "true"
-    } else {
-        false
-    };
-    if permission_denied {
-        return Err(FetchError::from(JsValue::from(HEADER_PERMISSION_DENIED)));
-    }
-    if status != 200 {
-        if let Ok(promise) = resp.text() {
-            if let Ok(val) = JsFuture::from(promise).await {
-                let msg = js_sys::JsString::from(val);
-                if msg.length() > 0 {
-                    return Err(FetchError::from(JsValue::from(msg)));
-                }
-            }
-        }
-        return Err(FetchError::from(JsValue::from(status)));
-    }
-    Ok(true)
-}
-
-pub fn socket_send(socket: &WebSocket, topic: String, payload: Vec<u8>) -> Result<(), io::Error> {
-    let msg = Publish::new(topic, QoS::AtMostOnce, payload);
-    let mut write = BytesMut::new();
-    if let Err(e) = msg.write(&mut write) {
-        log::warn!("!!Failed to write msg: {:?}", msg);
-        Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))
-    } else {
-        match socket.send_with_u8_array(write.as_ref()) {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                log::warn!("!!Error when mqtt sending: {:?}", e);
-                Err(io::Error::new(io::ErrorKind::BrokenPipe, format!("{:?}", e)))
-            }
-        }
-    }
-}
-
 // 将程序生成的blob用<a>元素下载到本地
 pub fn download_blob(url: &str, file_name: &str) -> Result<(), JsValue> {
     let a_ele = document().create_element("a")?;
@@ -377,43 +138,6 @@ pub fn download_blob(url: &str, file_name: &str) -> Result<(), JsValue> {
     Ok(())
 }
 
-fn create_ws_socket(
-    url: &str,
-    backend: &str,
-    on_open: &JsValue,
-    on_message: &JsValue,
-    on_error: &JsValue,
-    on_close: &JsValue,
-) -> Option<WebSocket> {
-    let backend = backend.trim().to_string();
-    if let Ok(protocol) = window().location().protocol() {
-        let proto = if protocol.starts_with("https") {
-            String::from("wss")
-        } else {
-            String::from("ws")
-        };
-        let backend_now = if !backend.is_empty() {
-            backend.replace("https://", "").replace("http://", "")
-        } else if let Ok(host) = window().location().host() {
-            host
-        } else {
-            return None;
-        };
-
-        let ws_uri = proto + "://" + &backend_now + url;
-        log::debug!("Connect to web socket server: {}", ws_uri);
-        if let Ok(socket) = WebSocket::new(&ws_uri) {
-            socket.set_binary_type(web_sys::BinaryType::Arraybuffer);
-            socket.set_onopen(Some(on_open.unchecked_ref()));
-            socket.set_onmessage(Some(on_message.unchecked_ref()));
-            socket.set_onerror(Some(on_error.unchecked_ref()));
-            socket.set_onclose(Some(on_close.unchecked_ref()));
-            return Some(socket);
-        }
-    }
-    None
-}
-
 /// 页面带version参数查询时拼装参数Map的方法
 pub fn get_version_param(ver: Option<u32>) -> Option<HashMap<String, String>> {
     if let Some(ver_now) = ver {
@@ -505,77 +229,6 @@ pub fn set_textarea_value_by_name(element_name: &str, content: &str) {
     }
 }
 
-pub struct MySocket {
-    url: String,
-    backend: String,
-    socket: Option<WebSocket>,
-    on_open: Closure<dyn Fn(JsValue)>,
-    on_message: Closure<dyn Fn(MessageEvent)>,
-    on_error: Closure<dyn Fn(ErrorEvent)>,
-    on_close: Closure<dyn Fn(CloseEvent)>,
-}
-
-impl MySocket {
-    pub fn new(
-        url: String,
-        backend: String,
-        on_open: Closure<dyn Fn(JsValue)>,
-        on_message: Closure<dyn Fn(MessageEvent)>,
-        on_error: Closure<dyn Fn(ErrorEvent)>,
-        on_close: Closure<dyn Fn(CloseEvent)>,
-    ) -> Self {
-        Self {
-            url,
-            backend,
-            socket: None,
-            on_open,
-            on_message,
-            on_error,
-            on_close,
-        }
-    }
-
-    pub fn close(&mut self) {
-        if let Some(s) = self.socket.take() {
-            s.close().unwrap();
-        }
-    }
-
-    pub fn connect(&mut self) -> bool {
-        let on_open = self.on_open.as_ref();
-        let on_message = self.on_message.as_ref();
-        let on_error = self.on_error.as_ref();
-        let on_close = self.on_close.as_ref();
-        let socket = create_ws_socket(&self.url, &self.backend, on_open,
-                                      on_message, on_error, on_close);
-        if let Some(s) = self.socket.take() {
-            s.close().unwrap();
-        }
-        self.socket = socket;
-        self.socket.is_some()
-    }
-
-    pub fn send<T>(&self, topic: &str, value: &T) -> bool
-    where
-        T: Serialize,
-    {
-        if self.socket.is_none() {
-            return false;
-        }
-        if let Ok(payload) = serde_cbor::to_vec(value) {
-            if let Err(e) = socket_send(self.socket.as_ref().unwrap(), topic.to_string(), payload) {
-                log::warn!("!!Failed to send REGISTER_TOPIC to server, {:?}", e);
-                return false;
-            }
-        }
-        true
-    }
-
-    pub fn is_connected(&self) -> bool {
-        self.socket.is_some() && self.socket.as_ref().unwrap().ready_state() == 1
-    }
-}
-
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum MyMsg {
     FileTree(components::filetree::Msg),
@@ -585,46 +238,15 @@ pub enum MyMsg {
     #[cfg(feature = "chart")]
     ChartMsg(chart::chartcard::Msg),
     Loading(components::myloading::LoadingMsg),
-}
-
-#[derive(Clone, Debug)]
-pub struct MyEventBus {
-    link: WorkerLink<Self>,
-    subscribers: HashSet<HandlerId>,
-}
-
-impl yew_agent::Worker for MyEventBus {
-    type Reach = Public<Self>;
-    type Message = ();
-    type Input = MyMsg;
-    type Output = MyMsg;
-
-    fn create(link: WorkerLink<Self>) -> Self {
-        Self {
-            link,
-            subscribers: HashSet::new(),
-        }
-    }
-
-    fn update(&mut self, _msg: Self::Message) {}
-
-    fn connected(&mut self, id: HandlerId) {
-        self.subscribers.insert(id);
-    }
-
-    fn handle_input(&mut self, msg: Self::Input, _id: HandlerId) {
-        for sub in self.subscribers.iter() {
-            self.link.respond(*sub, msg.clone());
-        }
-    }
-
-    fn disconnected(&mut self, id: HandlerId) {
-        self.subscribers.remove(&id);
-    }
-
-    fn name_of_resource() -> &'static str {
-        "bulma-worker.js"
-    }
+    /// Application messages, see `Channel`: its name and the message in CBOR
+    Channel(String, Vec<u8>),
+    /// Sent by a bridge to receive only the messages of these scopes, see `MyMsg::scope`.
+    /// Not delivered.
+    Subscribe(Vec<String>),
+    /// A question with the id to reply with, see `MyEventBus::request`
+    Request(u64, BusQuery),
+    /// The reply to the request with this id. Not delivered, it ends the request.
+    Reply(u64, BusReply),
 }
 
 pub fn create_table_div(table_head: Html, table_body: Html) -> Html {
@@ -643,35 +265,36 @@ pub fn create_table_div(table_head: Html, table_body: Html) -> Html {
 }
 
 /// 自定义的alert框
-/// 模拟window.alert弹窗，增加自定义样式
+/// 由页面中的`MyDialog`显示，没有`MyDialog`时使用window.alert
 pub fn my_alert(msg: &str) {
-    let cb = Function::new_no_args("");
-    my_alert_js(msg, &cb);
+    Dialog::alert(msg).show(|_| {});
 }
 
 /// 自定义的alert框，带回调函数
-/// 模拟window.alert弹窗，增加自定义样式
+/// 由页面中的`MyDialog`显示，没有`MyDialog`时使用window.alert
 pub fn my_alert_with_callback<F>(msg: &str, callback: F)
 where
     F: Fn() + 'static,
 {
-    let cb = Closure::wrap(Box::new(callback) as Box<dyn Fn()>);
-    my_alert_js(msg, cb.as_ref().unchecked_ref());
-    cb.forget();
+    Dialog::alert(msg).show(move |_| callback());
 }
 
 /// 自定义的confirm确认框
-/// 模拟window.confirm确认框，增加自定义样式
+/// 由页面中的`MyDialog`显示，没有`MyDialog`时使用window.confirm
 pub fn my_confirm<F>(msg: &str, callback: F)
 where
     F: Fn(bool) + 'static,
 {
-    let cb = Closure::wrap(Box::new(move |val: JsValue| {
-        let ok = val.as_bool().unwrap_or(false);
-        callback(ok);
-    }) as Box<dyn FnMut(JsValue)>);
-    my_confirm_js(msg, cb.as_ref().unchecked_ref());
-    cb.forget();
+    Dialog::confirm(msg).show(move |ok| callback(ok.is_some()));
+}
+
+/// 自定义的prompt输入框，取消时回调None
+/// 由页面中的`MyDialog`显示，没有`MyDialog`时使用window.prompt
+pub fn my_prompt<F>(msg: &str, value: &str, callback: F)
+where
+    F: Fn(Option<String>) + 'static,
+{
+    Dialog::prompt(msg).value(value).show(callback);
 }
 
 #[cfg(test)]
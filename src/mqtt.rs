@@ -0,0 +1,753 @@
+//! MQTT client session, independent of the transport.
+//!
+//! `MqttState` turns client requests into packets waiting in an outgoing buffer and
+//! turns inbound packets into `Event`s. `MySocket` drives it over a WebSocket.
+
+use std::collections::{HashMap, HashSet};
+
+use bytes::{Bytes, BytesMut};
+use derive_more::Display;
+
+use crate::publish::*;
+
+/// Options used when opening an MQTT session.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MqttOptions {
+    client_id: String,
+    keep_alive: u16,
+    clean_session: bool,
+    credentials: Option<Login>,
+    protocol: Protocol,
+    inflight: u16,
+}
+
+impl MqttOptions {
+    pub fn new<S: Into<String>>(client_id: S) -> Self {
+        Self {
+            client_id: client_id.into(),
+            keep_alive: 60,
+            clean_session: true,
+            credentials: None,
+            protocol: Protocol::V4,
+            inflight: 100,
+        }
+    }
+
+    /// Keep alive interval in seconds, zero turns PINGREQ off.
+    pub fn set_keep_alive(&mut self, secs: u16) -> &mut Self {
+        self.keep_alive = secs;
+        self
+    }
+
+    pub fn set_clean_session(&mut self, clean_session: bool) -> &mut Self {
+        self.clean_session = clean_session;
+        self
+    }
+
+    pub fn set_credentials<U: Into<String>, P: Into<String>>(&mut self, username: U, password: P) -> &mut Self {
+        self.credentials = Some(Login {
+            username: username.into(),
+            password: password.into(),
+        });
+        self
+    }
+
+    pub fn set_protocol(&mut self, protocol: Protocol) -> &mut Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Maximum number of outgoing QoS 1 and 2 publishes waiting for acknowledgement.
+    pub fn set_inflight(&mut self, inflight: u16) -> &mut Self {
+        self.inflight = inflight;
+        self
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    pub fn keep_alive(&self) -> u16 {
+        self.keep_alive
+    }
+
+    pub fn clean_session(&self) -> bool {
+        self.clean_session
+    }
+
+    pub fn credentials(&self) -> Option<&Login> {
+        self.credentials.as_ref()
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn inflight(&self) -> u16 {
+        self.inflight
+    }
+}
+
+/// Something the broker told us.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Connected(ConnAck),
+    Publish(Publish),
+    /// A QoS 1 publish of ours was delivered.
+    PubAck(PubAck),
+    /// A QoS 2 publish of ours was delivered.
+    PubComp(PubComp),
+    SubAck(SubAck),
+    UnsubAck(UnsubAck),
+    PingResp,
+}
+
+#[derive(Debug, Clone, PartialEq, Display)]
+pub enum StateError {
+    #[display("malformed mqtt packet: {_0:?}")]
+    Packet(Error),
+    #[display("mqtt session is not connected")]
+    NotConnected,
+    #[display("connection refused by broker, code {_0}")]
+    ConnectionRefused(u8),
+    #[display("broker did not answer the last ping")]
+    AwaitPingResp,
+    #[display("unsolicited ack, pkid {_0}")]
+    Unsolicited(u16),
+    #[display("unexpected packet from broker")]
+    UnexpectedPacket,
+    #[display("too many publishes waiting for acknowledgement")]
+    InflightFull,
+}
+
+impl std::error::Error for StateError {}
+
+impl From<Error> for StateError {
+    fn from(e: Error) -> Self {
+        StateError::Packet(e)
+    }
+}
+
+pub struct MqttState {
+    options: MqttOptions,
+    connected: bool,
+    await_pingresp: bool,
+    last_pkid: u16,
+    pending_subscribe: HashMap<u16, Vec<SubscribeFilter>>,
+    pending_unsubscribe: HashMap<u16, Vec<String>>,
+    /// Filters acknowledged by the broker and the granted QoS
+    subscriptions: HashMap<String, QoS>,
+    /// Our QoS 1 and 2 publishes waiting for PUBACK or PUBREC, in sending order
+    outgoing_pub: Vec<Publish>,
+    /// PUBREL sent, waiting for PUBCOMP
+    outgoing_rel: Vec<u16>,
+    /// QoS 2 publishes delivered to us, waiting for PUBREL
+    incoming_pub: HashSet<u16>,
+    write: BytesMut,
+}
+
+impl MqttState {
+    pub fn new(options: MqttOptions) -> Self {
+        Self {
+            options,
+            connected: false,
+            await_pingresp: false,
+            last_pkid: 0,
+            pending_subscribe: HashMap::new(),
+            pending_unsubscribe: HashMap::new(),
+            subscriptions: HashMap::new(),
+            outgoing_pub: Vec::new(),
+            outgoing_rel: Vec::new(),
+            incoming_pub: HashSet::new(),
+            write: BytesMut::new(),
+        }
+    }
+
+    pub fn options(&self) -> &MqttOptions {
+        &self.options
+    }
+
+    /// True after the broker accepted CONNECT and until the connection is lost.
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    pub fn subscriptions(&self) -> &HashMap<String, QoS> {
+        &self.subscriptions
+    }
+
+    /// Packet ids of our QoS 1 and 2 publishes not yet acknowledged.
+    pub fn inflight(&self) -> Vec<u16> {
+        self.outgoing_pub.iter().map(|p| p.pkid).chain(self.outgoing_rel.iter().copied()).collect()
+    }
+
+    /// Start a new session, the transport must be open.
+    /// Unacknowledged publishes are sent again once the broker accepts it.
+    pub fn connect(&mut self) -> Result<(), StateError> {
+        self.connection_lost();
+        self.write.clear();
+        if self.options.clean_session {
+            self.incoming_pub.clear();
+        }
+        let connect = Connect {
+            protocol: self.options.protocol,
+            keep_alive: self.options.keep_alive,
+            client_id: self.options.client_id.clone(),
+            clean_session: self.options.clean_session,
+            login: self.options.credentials.clone(),
+        };
+        connect.write(&mut self.write)?;
+        Ok(())
+    }
+
+    pub fn disconnect(&mut self) -> Result<(), StateError> {
+        if self.connected {
+            Packet::Disconnect.write(self.options.protocol, &mut self.write)?;
+        }
+        self.connection_lost();
+        Ok(())
+    }
+
+    /// Forget everything bound to the current connection.
+    /// Pending requests are settled as if acknowledged, so they are restored after reconnecting.
+    pub fn connection_lost(&mut self) {
+        self.connected = false;
+        self.await_pingresp = false;
+        for (_, filters) in self.pending_subscribe.drain() {
+            for filter in filters {
+                self.subscriptions.insert(filter.path, filter.qos);
+            }
+        }
+        for (_, topics) in self.pending_unsubscribe.drain() {
+            for topic in topics {
+                self.subscriptions.remove(&topic);
+            }
+        }
+    }
+
+    /// Give up every unacknowledged publish, returns their packet ids.
+    pub fn abandon_inflight(&mut self) -> Vec<u16> {
+        let pkids = self.inflight();
+        self.outgoing_pub.clear();
+        self.outgoing_rel.clear();
+        pkids
+    }
+
+    /// Returns the packet id given to the publish, zero for QoS 0.
+    pub fn publish(&mut self, mut publish: Publish) -> Result<u16, StateError> {
+        self.check_connected()?;
+        if publish.qos == QoS::AtMostOnce {
+            publish.pkid = 0;
+            publish.write(self.options.protocol, &mut self.write)?;
+            return Ok(0);
+        }
+        if self.outgoing_pub.len() + self.outgoing_rel.len() >= self.options.inflight as usize {
+            return Err(StateError::InflightFull);
+        }
+        publish.pkid = self.next_pkid();
+        publish.write(self.options.protocol, &mut self.write)?;
+        let pkid = publish.pkid;
+        self.outgoing_pub.push(publish);
+        Ok(pkid)
+    }
+
+    pub fn subscribe<S: Into<String>>(&mut self, filter: S, qos: QoS) -> Result<u16, StateError> {
+        self.check_connected()?;
+        let pkid = self.next_pkid();
+        let filters = vec![SubscribeFilter {
+            path: filter.into(),
+            qos,
+        }];
+        let subscribe = Subscribe { pkid, filters };
+        subscribe.write(self.options.protocol, &mut self.write)?;
+        self.pending_subscribe.insert(pkid, subscribe.filters);
+        Ok(pkid)
+    }
+
+    pub fn unsubscribe<S: Into<String>>(&mut self, filter: S) -> Result<u16, StateError> {
+        self.check_connected()?;
+        let pkid = self.next_pkid();
+        let unsubscribe = Unsubscribe {
+            pkid,
+            topics: vec![filter.into()],
+        };
+        unsubscribe.write(self.options.protocol, &mut self.write)?;
+        self.pending_unsubscribe.insert(pkid, unsubscribe.topics);
+        Ok(pkid)
+    }
+
+    /// Called every keep alive interval, fails if the previous ping was not answered.
+    pub fn ping(&mut self) -> Result<(), StateError> {
+        self.check_connected()?;
+        if self.await_pingresp {
+            return Err(StateError::AwaitPingResp);
+        }
+        Packet::PingReq.write(self.options.protocol, &mut self.write)?;
+        self.await_pingresp = true;
+        Ok(())
+    }
+
+    pub fn handle_incoming(&mut self, packet: Packet) -> Result<Option<Event>, StateError> {
+        match packet {
+            Packet::ConnAck(ack) => {
+                if ack.code != 0 {
+                    return Err(StateError::ConnectionRefused(ack.code));
+                }
+                self.connected = true;
+                let protocol = self.options.protocol;
+                for publish in self.outgoing_pub.iter_mut() {
+                    publish.dup = true;
+                    publish.write(protocol, &mut self.write)?;
+                }
+                for pkid in self.outgoing_rel.iter() {
+                    Packet::PubRel(PubRel { pkid: *pkid }).write(protocol, &mut self.write)?;
+                }
+                // the broker has no session for us, subscribe to everything again
+                if !ack.session_present && !self.subscriptions.is_empty() {
+                    let pkid = self.next_pkid();
+                    let filters = self
+                        .subscriptions
+                        .iter()
+                        .map(|(path, qos)| SubscribeFilter {
+                            path: path.clone(),
+                            qos: *qos,
+                        })
+                        .collect();
+                    let subscribe = Subscribe { pkid, filters };
+                    subscribe.write(protocol, &mut self.write)?;
+                    self.pending_subscribe.insert(pkid, subscribe.filters);
+                }
+                Ok(Some(Event::Connected(ack)))
+            }
+            Packet::Publish(publish) => {
+                let protocol = self.options.protocol;
+                match publish.qos {
+                    QoS::AtMostOnce => Ok(Some(Event::Publish(publish))),
+                    QoS::AtLeastOnce => {
+                        Packet::PubAck(PubAck { pkid: publish.pkid }).write(protocol, &mut self.write)?;
+                        Ok(Some(Event::Publish(publish)))
+                    }
+                    QoS::ExactlyOnce => {
+                        Packet::PubRec(PubRec { pkid: publish.pkid }).write(protocol, &mut self.write)?;
+                        // a retransmission of a publish already delivered
+                        if self.incoming_pub.insert(publish.pkid) {
+                            Ok(Some(Event::Publish(publish)))
+                        } else {
+                            Ok(None)
+                        }
+                    }
+                }
+            }
+            Packet::PubAck(ack) => {
+                self.remove_outgoing_pub(ack.pkid, QoS::AtLeastOnce)?;
+                Ok(Some(Event::PubAck(ack)))
+            }
+            Packet::PubRec(rec) => {
+                self.remove_outgoing_pub(rec.pkid, QoS::ExactlyOnce)?;
+                self.outgoing_rel.push(rec.pkid);
+                Packet::PubRel(PubRel { pkid: rec.pkid }).write(self.options.protocol, &mut self.write)?;
+                Ok(None)
+            }
+            Packet::PubRel(rel) => {
+                self.incoming_pub.remove(&rel.pkid);
+                Packet::PubComp(PubComp { pkid: rel.pkid }).write(self.options.protocol, &mut self.write)?;
+                Ok(None)
+            }
+            Packet::PubComp(comp) => {
+                let index = self
+                    .outgoing_rel
+                    .iter()
+                    .position(|pkid| *pkid == comp.pkid)
+                    .ok_or(StateError::Unsolicited(comp.pkid))?;
+                self.outgoing_rel.remove(index);
+                Ok(Some(Event::PubComp(comp)))
+            }
+            Packet::SubAck(ack) => {
+                let filters = self
+                    .pending_subscribe
+                    .remove(&ack.pkid)
+                    .ok_or(StateError::Unsolicited(ack.pkid))?;
+                for (filter, code) in filters.into_iter().zip(ack.return_codes.iter()) {
+                    match code {
+                        SubscribeReasonCode::Success(qos) => {
+                            self.subscriptions.insert(filter.path, *qos);
+                        }
+                        SubscribeReasonCode::Failure => {
+                            self.subscriptions.remove(&filter.path);
+                        }
+                    }
+                }
+                Ok(Some(Event::SubAck(ack)))
+            }
+            Packet::UnsubAck(ack) => {
+                let topics = self
+                    .pending_unsubscribe
+                    .remove(&ack.pkid)
+                    .ok_or(StateError::Unsolicited(ack.pkid))?;
+                for topic in topics {
+                    self.subscriptions.remove(&topic);
+                }
+                Ok(Some(Event::UnsubAck(ack)))
+            }
+            Packet::PingResp => {
+                self.await_pingresp = false;
+                Ok(Some(Event::PingResp))
+            }
+            _ => Err(StateError::UnexpectedPacket),
+        }
+    }
+
+    /// Bytes to be written to the transport, if any.
+    pub fn take_outgoing(&mut self) -> Option<Bytes> {
+        if self.write.is_empty() {
+            None
+        } else {
+            Some(self.write.split().freeze())
+        }
+    }
+
+    fn check_connected(&self) -> Result<(), StateError> {
+        if self.connected {
+            Ok(())
+        } else {
+            Err(StateError::NotConnected)
+        }
+    }
+
+    fn remove_outgoing_pub(&mut self, pkid: u16, qos: QoS) -> Result<Publish, StateError> {
+        let index = self
+            .outgoing_pub
+            .iter()
+            .position(|p| p.pkid == pkid && p.qos == qos)
+            .ok_or(StateError::Unsolicited(pkid))?;
+        Ok(self.outgoing_pub.remove(index))
+    }
+
+    fn next_pkid(&mut self) -> u16 {
+        loop {
+            self.last_pkid = self.last_pkid.wrapping_add(1);
+            if self.last_pkid == 0 {
+                continue;
+            }
+            let pkid = self.last_pkid;
+            if !self.pending_subscribe.contains_key(&pkid)
+                && !self.pending_unsubscribe.contains_key(&pkid)
+                && !self.outgoing_pub.iter().any(|p| p.pkid == pkid)
+                && !self.outgoing_rel.contains(&pkid)
+            {
+                return pkid;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// In-memory stand-in for a broker serving a single client.
+    struct Broker {
+        protocol: Protocol,
+        decoder: PacketDecoder,
+        connack_code: u8,
+        subscriptions: Vec<(String, QoS)>,
+        /// Publishes of the client acknowledged by the broker
+        received: Vec<Publish>,
+        /// Packet ids of the broker's publishes acknowledged by the client
+        acked: Vec<u16>,
+        last_pkid: u16,
+        write: BytesMut,
+    }
+
+    impl Broker {
+        fn new(protocol: Protocol) -> Self {
+            Self {
+                protocol,
+                decoder: PacketDecoder::new(protocol),
+                connack_code: 0,
+                subscriptions: Vec::new(),
+                received: Vec::new(),
+                acked: Vec::new(),
+                last_pkid: 0,
+                write: BytesMut::new(),
+            }
+        }
+
+        fn handle(&mut self, data: &[u8]) {
+            for packet in self.decoder.push(data).unwrap() {
+                for reply in self.reply(packet) {
+                    reply.write(self.protocol, &mut self.write).unwrap();
+                }
+            }
+        }
+
+        fn reply(&mut self, packet: Packet) -> Vec<Packet> {
+            match packet {
+                Packet::Connect(_) => vec![Packet::ConnAck(ConnAck {
+                    session_present: false,
+                    code: self.connack_code,
+                })],
+                Packet::Subscribe(s) => {
+                    let return_codes = s
+                        .filters
+                        .iter()
+                        .map(|f| {
+                            self.subscriptions.push((f.path.clone(), f.qos));
+                            SubscribeReasonCode::Success(f.qos)
+                        })
+                        .collect();
+                    vec![Packet::SubAck(SubAck {
+                        pkid: s.pkid,
+                        return_codes,
+                    })]
+                }
+                Packet::Unsubscribe(u) => {
+                    self.subscriptions.retain(|(s, _)| !u.topics.contains(s));
+                    vec![Packet::UnsubAck(UnsubAck { pkid: u.pkid })]
+                }
+                Packet::Publish(p) => {
+                    let mut replies = match p.qos {
+                        QoS::AtMostOnce => vec![],
+                        QoS::AtLeastOnce => vec![Packet::PubAck(PubAck { pkid: p.pkid })],
+                        QoS::ExactlyOnce => vec![Packet::PubRec(PubRec { pkid: p.pkid })],
+                    };
+                    if let Some((_, qos)) = self.subscriptions.iter().find(|(s, _)| *s == p.topic) {
+                        let mut forward = Publish::new(p.topic.clone(), p.qos, p.payload.clone());
+                        if *qos < forward.qos {
+                            forward.qos = *qos;
+                        }
+                        if forward.qos != QoS::AtMostOnce {
+                            self.last_pkid += 1;
+                            forward.pkid = self.last_pkid;
+                        }
+                        replies.push(Packet::Publish(forward));
+                    }
+                    self.received.push(p);
+                    replies
+                }
+                Packet::PubAck(ack) => {
+                    self.acked.push(ack.pkid);
+                    vec![]
+                }
+                Packet::PubRec(rec) => vec![Packet::PubRel(PubRel { pkid: rec.pkid })],
+                Packet::PubRel(rel) => vec![Packet::PubComp(PubComp { pkid: rel.pkid })],
+                Packet::PubComp(comp) => {
+                    self.acked.push(comp.pkid);
+                    vec![]
+                }
+                Packet::PingReq => vec![Packet::PingResp],
+                _ => vec![],
+            }
+        }
+    }
+
+    /// Exchange packets until both sides are quiet, returns the events of the client.
+    fn pump(client: &mut MqttState, broker: &mut Broker) -> Result<Vec<Event>, StateError> {
+        let mut decoder = PacketDecoder::new(client.options().protocol());
+        let mut events = Vec::new();
+        while let Some(data) = client.take_outgoing() {
+            broker.handle(&data);
+            let data = broker.write.split();
+            for packet in decoder.push(&data)? {
+                events.extend(client.handle_incoming(packet)?);
+            }
+        }
+        Ok(events)
+    }
+
+    fn connected(protocol: Protocol) -> (MqttState, Broker) {
+        let mut options = MqttOptions::new("test-client");
+        options.set_credentials("user", "pass").set_protocol(protocol);
+        let mut client = MqttState::new(options);
+        let mut broker = Broker::new(protocol);
+        client.connect().unwrap();
+        let events = pump(&mut client, &mut broker).unwrap();
+        assert!(matches!(events[..], [Event::Connected(_)]));
+        assert!(client.is_connected());
+        (client, broker)
+    }
+
+    #[test]
+    fn test_subscribe_and_receive() {
+        for protocol in [Protocol::V4, Protocol::V5] {
+            let (mut client, mut broker) = connected(protocol);
+            let pkid = client.subscribe("plant/1/measure", QoS::AtMostOnce).unwrap();
+            match &pump(&mut client, &mut broker).unwrap()[..] {
+                [Event::SubAck(ack)] => {
+                    assert_eq!(ack.pkid, pkid);
+                    assert_eq!(ack.return_codes, vec![SubscribeReasonCode::Success(QoS::AtMostOnce)]);
+                }
+                e => panic!("unexpected events {:?}", e),
+            }
+            assert!(client.subscriptions().contains_key("plant/1/measure"));
+
+            client
+                .publish(Publish::new("plant/1/measure", QoS::AtMostOnce, b"42".to_vec()))
+                .unwrap();
+            match &pump(&mut client, &mut broker).unwrap()[..] {
+                [Event::Publish(p)] => {
+                    assert_eq!(p.topic, "plant/1/measure");
+                    assert_eq!(p.payload.as_ref(), b"42");
+                }
+                e => panic!("unexpected events {:?}", e),
+            }
+
+            client.unsubscribe("plant/1/measure").unwrap();
+            assert!(matches!(pump(&mut client, &mut broker).unwrap()[..], [Event::UnsubAck(_)]));
+            assert!(client.subscriptions().is_empty());
+            client
+                .publish(Publish::new("plant/1/measure", QoS::AtMostOnce, b"43".to_vec()))
+                .unwrap();
+            assert_eq!(pump(&mut client, &mut broker), Ok(vec![]));
+        }
+    }
+
+    #[test]
+    fn test_connect_refused() {
+        let mut client = MqttState::new(MqttOptions::new("test-client"));
+        let mut broker = Broker::new(Protocol::V4);
+        broker.connack_code = 5;
+        client.connect().unwrap();
+        assert_eq!(pump(&mut client, &mut broker), Err(StateError::ConnectionRefused(5)));
+        assert!(!client.is_connected());
+        assert_eq!(client.subscribe("a", QoS::AtMostOnce), Err(StateError::NotConnected));
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let (mut client, mut broker) = connected(Protocol::V4);
+        client.ping().unwrap();
+        assert_eq!(pump(&mut client, &mut broker), Ok(vec![Event::PingResp]));
+        client.ping().unwrap();
+        // broker never answered
+        client.take_outgoing();
+        assert_eq!(client.ping(), Err(StateError::AwaitPingResp));
+    }
+
+    #[test]
+    fn test_unsolicited_ack() {
+        let (mut client, _) = connected(Protocol::V4);
+        let ack = Packet::SubAck(SubAck {
+            pkid: 7,
+            return_codes: vec![SubscribeReasonCode::Failure],
+        });
+        assert_eq!(client.handle_incoming(ack), Err(StateError::Unsolicited(7)));
+        let ack = Packet::PubAck(PubAck { pkid: 8 });
+        assert_eq!(client.handle_incoming(ack), Err(StateError::Unsolicited(8)));
+    }
+
+    #[test]
+    fn test_qos1_and_qos2() {
+        for protocol in [Protocol::V4, Protocol::V5] {
+            let (mut client, mut broker) = connected(protocol);
+            client.subscribe("setpoint", QoS::ExactlyOnce).unwrap();
+            pump(&mut client, &mut broker).unwrap();
+
+            let pkid = client
+                .publish(Publish::new("setpoint", QoS::AtLeastOnce, b"1".to_vec()))
+                .unwrap();
+            assert_ne!(pkid, 0);
+            assert_eq!(client.inflight(), vec![pkid]);
+            let events = pump(&mut client, &mut broker).unwrap();
+            assert!(events.contains(&Event::PubAck(PubAck { pkid })));
+            assert!(events.iter().any(|e| matches!(e, Event::Publish(p) if p.qos == QoS::AtLeastOnce)));
+            assert!(client.inflight().is_empty());
+            // the echo was acknowledged by the client
+            assert_eq!(broker.acked, vec![1]);
+
+            let pkid = client
+                .publish(Publish::new("setpoint", QoS::ExactlyOnce, b"2".to_vec()))
+                .unwrap();
+            let events = pump(&mut client, &mut broker).unwrap();
+            assert!(events.contains(&Event::PubComp(PubComp { pkid })));
+            let delivered: Vec<_> = events.iter().filter(|e| matches!(e, Event::Publish(_))).collect();
+            assert_eq!(delivered.len(), 1);
+            assert!(client.inflight().is_empty());
+            assert_eq!(broker.acked, vec![1, 2]);
+        }
+    }
+
+    #[test]
+    fn test_qos2_duplicate_delivered_once() {
+        let (mut client, _) = connected(Protocol::V4);
+        let mut publish = Publish::new("setpoint", QoS::ExactlyOnce, b"1".to_vec());
+        publish.pkid = 3;
+        let events = client.handle_incoming(Packet::Publish(publish.clone())).unwrap();
+        assert!(matches!(events, Some(Event::Publish(_))));
+        publish.dup = true;
+        assert_eq!(client.handle_incoming(Packet::Publish(publish.clone())), Ok(None));
+        client.handle_incoming(Packet::PubRel(PubRel { pkid: 3 })).unwrap();
+        // a new message may reuse the id once released
+        assert!(client.handle_incoming(Packet::Publish(publish)).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_retransmit_after_reconnect() {
+        let (mut client, mut broker) = connected(Protocol::V4);
+        let pkid1 = client
+            .publish(Publish::new("setpoint", QoS::AtLeastOnce, b"1".to_vec()))
+            .unwrap();
+        let pkid2 = client
+            .publish(Publish::new("setpoint", QoS::ExactlyOnce, b"2".to_vec()))
+            .unwrap();
+        // connection drops before the broker sees anything
+        client.take_outgoing();
+        client.connection_lost();
+        assert_eq!(client.inflight(), vec![pkid1, pkid2]);
+
+        client.connect().unwrap();
+        let events = pump(&mut client, &mut broker).unwrap();
+        assert!(events.contains(&Event::PubAck(PubAck { pkid: pkid1 })));
+        assert!(events.contains(&Event::PubComp(PubComp { pkid: pkid2 })));
+        assert!(broker.received.iter().all(|p| p.dup));
+        assert_eq!(broker.received.iter().map(|p| p.pkid).collect::<Vec<_>>(), vec![pkid1, pkid2]);
+        assert!(client.inflight().is_empty());
+    }
+
+    #[test]
+    fn test_resubscribe_after_reconnect() {
+        let (mut client, mut broker) = connected(Protocol::V4);
+        client.subscribe("plant/+/measure", QoS::AtLeastOnce).unwrap();
+        client.subscribe("alarm/#", QoS::AtMostOnce).unwrap();
+        pump(&mut client, &mut broker).unwrap();
+        // sent but never acknowledged
+        client.subscribe("setpoint", QoS::ExactlyOnce).unwrap();
+        client.unsubscribe("alarm/#").unwrap();
+        client.take_outgoing();
+        client.connection_lost();
+
+        // a new broker without our session
+        let mut broker = Broker::new(Protocol::V4);
+        client.connect().unwrap();
+        let events = pump(&mut client, &mut broker).unwrap();
+        assert!(matches!(events[..], [Event::Connected(_), Event::SubAck(_)]));
+        broker.subscriptions.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            broker.subscriptions,
+            vec![
+                ("plant/+/measure".to_string(), QoS::AtLeastOnce),
+                ("setpoint".to_string(), QoS::ExactlyOnce),
+            ]
+        );
+        assert_eq!(client.subscriptions().len(), 2);
+    }
+
+    #[test]
+    fn test_inflight_limit() {
+        let mut options = MqttOptions::new("test-client");
+        options.set_inflight(2);
+        let mut client = MqttState::new(options);
+        let mut broker = Broker::new(Protocol::V4);
+        client.connect().unwrap();
+        pump(&mut client, &mut broker).unwrap();
+        for _ in 0..2 {
+            client
+                .publish(Publish::new("setpoint", QoS::AtLeastOnce, b"1".to_vec()))
+                .unwrap();
+        }
+        let publish = Publish::new("setpoint", QoS::AtLeastOnce, b"1".to_vec());
+        assert_eq!(client.publish(publish), Err(StateError::InflightFull));
+        assert_eq!(client.abandon_inflight().len(), 2);
+        assert!(client.inflight().is_empty());
+    }
+}
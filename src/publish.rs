@@ -1,4 +1,4 @@
-use bytes::{BufMut, Bytes, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use core::fmt;
 use std::fmt::Debug;
 
@@ -6,8 +6,24 @@ use std::fmt::Debug;
 pub enum Error {
     PacketIdZero,
     PayloadTooLong,
+    MalformedPacket,
+    InvalidPacketType(u8),
+    InvalidProtocolLevel(u8),
+    /// Remaining length is encoded with more than four bytes
+    MalformedRemainingLength,
+    /// The frame is not complete yet, at least this many more bytes are needed
+    InsufficientBytes(usize),
+    /// Topic length prefix points past the end of the packet
+    TruncatedTopic,
+    TopicNotUtf8,
+    InvalidQoS(u8),
+    /// Remaining length is larger than the decoder accepts
+    PacketTooLarge(usize),
 }
 
+/// Largest remaining length a fixed header can encode.
+pub const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
 pub enum QoS {
@@ -16,6 +32,39 @@ pub enum QoS {
     ExactlyOnce = 2,
 }
 
+/// MQTT protocol revision spoken on the wire.
+/// Version 5 packets are written without properties, inbound properties are skipped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    /// MQTT 3.1.1
+    #[default]
+    V4,
+    /// MQTT 5.0
+    V5,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Login {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Connect {
+    pub protocol: Protocol,
+    pub keep_alive: u16,
+    pub client_id: String,
+    pub clean_session: bool,
+    pub login: Option<Login>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnAck {
+    pub session_present: bool,
+    /// Return code (3.1.1) or reason code (5.0), zero means accepted.
+    pub code: u8,
+}
+
 #[derive(Clone, PartialEq)]
 pub struct Publish {
     pub qos: QoS,
@@ -26,6 +75,355 @@ pub struct Publish {
     pub retain: bool,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PubAck {
+    pub pkid: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PubRec {
+    pub pkid: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PubRel {
+    pub pkid: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PubComp {
+    pub pkid: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubscribeFilter {
+    pub path: String,
+    pub qos: QoS,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subscribe {
+    pub pkid: u16,
+    pub filters: Vec<SubscribeFilter>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscribeReasonCode {
+    Success(QoS),
+    Failure,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubAck {
+    pub pkid: u16,
+    pub return_codes: Vec<SubscribeReasonCode>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unsubscribe {
+    pub pkid: u16,
+    pub topics: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnsubAck {
+    pub pkid: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Packet {
+    Connect(Connect),
+    ConnAck(ConnAck),
+    Publish(Publish),
+    PubAck(PubAck),
+    PubRec(PubRec),
+    PubRel(PubRel),
+    PubComp(PubComp),
+    Subscribe(Subscribe),
+    SubAck(SubAck),
+    Unsubscribe(Unsubscribe),
+    UnsubAck(UnsubAck),
+    PingReq,
+    PingResp,
+    Disconnect,
+}
+
+impl Packet {
+    pub fn write(&self, protocol: Protocol, buffer: &mut BytesMut) -> Result<usize, Error> {
+        match self {
+            Packet::Connect(p) => p.write(buffer),
+            Packet::ConnAck(p) => p.write(protocol, buffer),
+            Packet::Publish(p) => p.write(protocol, buffer),
+            Packet::PubAck(p) => write_pkid(buffer, 0x40, p.pkid),
+            Packet::PubRec(p) => write_pkid(buffer, 0x50, p.pkid),
+            Packet::PubRel(p) => write_pkid(buffer, 0x62, p.pkid),
+            Packet::PubComp(p) => write_pkid(buffer, 0x70, p.pkid),
+            Packet::Subscribe(p) => p.write(protocol, buffer),
+            Packet::SubAck(p) => p.write(protocol, buffer),
+            Packet::Unsubscribe(p) => p.write(protocol, buffer),
+            Packet::UnsubAck(p) => p.write(protocol, buffer),
+            Packet::PingReq => write_empty(buffer, 0xC0),
+            Packet::PingResp => write_empty(buffer, 0xD0),
+            Packet::Disconnect => write_empty(buffer, 0xE0),
+        }
+    }
+}
+
+/// Fixed header found at the start of every frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedHeader {
+    pub byte1: u8,
+    /// Length of the fixed header itself, packet type byte and remaining length
+    pub header_len: usize,
+    pub remaining_len: usize,
+}
+
+impl FixedHeader {
+    pub fn packet_type(&self) -> u8 {
+        self.byte1 >> 4
+    }
+
+    pub fn frame_len(&self) -> usize {
+        self.header_len + self.remaining_len
+    }
+}
+
+/// Counterpart of `write_remaining_length`, returns the length and the number of bytes it used.
+pub fn read_remaining_length(stream: &[u8]) -> Result<(usize, usize), Error> {
+    let mut len = 0;
+    for (i, byte) in stream.iter().enumerate() {
+        if i == 4 {
+            return Err(Error::MalformedRemainingLength);
+        }
+        len += ((byte & 0x7F) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((len, i + 1));
+        }
+    }
+    if stream.len() >= 4 {
+        Err(Error::MalformedRemainingLength)
+    } else {
+        Err(Error::InsufficientBytes(1))
+    }
+}
+
+/// Check that `stream` starts with a complete frame, without consuming it.
+pub fn check(stream: &[u8], max_packet_size: usize) -> Result<FixedHeader, Error> {
+    if stream.len() < 2 {
+        return Err(Error::InsufficientBytes(2 - stream.len()));
+    }
+    let (remaining_len, count) = read_remaining_length(&stream[1..])?;
+    if remaining_len > max_packet_size {
+        return Err(Error::PacketTooLarge(remaining_len));
+    }
+    let header = FixedHeader {
+        byte1: stream[0],
+        header_len: 1 + count,
+        remaining_len,
+    };
+    if stream.len() < header.frame_len() {
+        return Err(Error::InsufficientBytes(header.frame_len() - stream.len()));
+    }
+    Ok(header)
+}
+
+/// Decode one complete packet, `frame` must hold exactly the fixed header and the remaining bytes.
+pub fn read(protocol: Protocol, mut frame: Bytes) -> Result<Packet, Error> {
+    let header = check(&frame, MAX_REMAINING_LENGTH)?;
+    if frame.len() != header.frame_len() {
+        return Err(Error::MalformedPacket);
+    }
+    frame.advance(header.header_len);
+    read_body(protocol, header.byte1, frame)
+}
+
+/// Take the first complete packet out of `stream`, `Ok(None)` when more bytes are needed.
+pub fn read_packet(protocol: Protocol, stream: &mut BytesMut, max_packet_size: usize) -> Result<Option<Packet>, Error> {
+    let header = match check(stream, max_packet_size) {
+        Ok(header) => header,
+        Err(Error::InsufficientBytes(_)) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut frame = stream.split_to(header.frame_len()).freeze();
+    frame.advance(header.header_len);
+    read_body(protocol, header.byte1, frame).map(Some)
+}
+
+fn read_body(protocol: Protocol, byte1: u8, frame: Bytes) -> Result<Packet, Error> {
+    let packet = match byte1 >> 4 {
+        1 => Packet::Connect(Connect::read(frame)?),
+        2 => Packet::ConnAck(ConnAck::read(protocol, frame)?),
+        3 => Packet::Publish(Publish::read(protocol, byte1, frame)?),
+        4 => Packet::PubAck(PubAck { pkid: read_pkid(frame)? }),
+        5 => Packet::PubRec(PubRec { pkid: read_pkid(frame)? }),
+        6 => Packet::PubRel(PubRel { pkid: read_pkid(frame)? }),
+        7 => Packet::PubComp(PubComp { pkid: read_pkid(frame)? }),
+        8 => Packet::Subscribe(Subscribe::read(protocol, frame)?),
+        9 => Packet::SubAck(SubAck::read(protocol, frame)?),
+        10 => Packet::Unsubscribe(Unsubscribe::read(protocol, frame)?),
+        11 => Packet::UnsubAck(UnsubAck::read(protocol, frame)?),
+        12 => Packet::PingReq,
+        13 => Packet::PingResp,
+        14 => Packet::Disconnect,
+        t => return Err(Error::InvalidPacketType(t)),
+    };
+    Ok(packet)
+}
+
+/// Reassembles packets from transport messages, one WebSocket message may carry
+/// part of a frame or several frames.
+pub struct PacketDecoder {
+    protocol: Protocol,
+    max_packet_size: usize,
+    buffer: BytesMut,
+}
+
+impl PacketDecoder {
+    pub fn new(protocol: Protocol) -> Self {
+        Self {
+            protocol,
+            max_packet_size: MAX_REMAINING_LENGTH,
+            buffer: BytesMut::new(),
+        }
+    }
+
+    pub fn set_max_packet_size(&mut self, size: usize) -> &mut Self {
+        self.max_packet_size = size;
+        self
+    }
+
+    /// Append `data` and decode every packet completed by it.
+    /// A decode error leaves the stream unusable, the buffer is dropped.
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<Packet>, Error> {
+        self.buffer.extend_from_slice(data);
+        let mut packets = Vec::new();
+        loop {
+            match read_packet(self.protocol, &mut self.buffer, self.max_packet_size) {
+                Ok(Some(packet)) => packets.push(packet),
+                Ok(None) => return Ok(packets),
+                Err(e) => {
+                    self.buffer.clear();
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Bytes of an incomplete frame waiting for the rest.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+    }
+}
+
+impl Connect {
+    fn len(&self) -> usize {
+        // protocol name, level, flags and keep alive
+        let mut len = 2 + "MQTT".len() + 1 + 1 + 2;
+        len += properties_len(self.protocol);
+        len += 2 + self.client_id.len();
+        if let Some(login) = &self.login {
+            len += 2 + login.username.len() + 2 + login.password.len();
+        }
+        len
+    }
+
+    pub fn write(&self, buffer: &mut BytesMut) -> Result<usize, Error> {
+        let len = self.len();
+        buffer.reserve(5 + len);
+
+        buffer.put_u8(0x10);
+        let count = write_remaining_length(buffer, len)?;
+        write_mqtt_string(buffer, "MQTT");
+        buffer.put_u8(match self.protocol {
+            Protocol::V4 => 4,
+            Protocol::V5 => 5,
+        });
+        let mut flags = 0;
+        if self.clean_session {
+            flags |= 0x02;
+        }
+        if self.login.is_some() {
+            flags |= 0xC0;
+        }
+        buffer.put_u8(flags);
+        buffer.put_u16(self.keep_alive);
+        write_properties(self.protocol, buffer);
+        write_mqtt_string(buffer, &self.client_id);
+        if let Some(login) = &self.login {
+            write_mqtt_string(buffer, &login.username);
+            write_mqtt_string(buffer, &login.password);
+        }
+        Ok(1 + count + len)
+    }
+
+    fn read(mut bytes: Bytes) -> Result<Connect, Error> {
+        let _name = read_mqtt_string(&mut bytes)?;
+        let protocol = match read_u8(&mut bytes)? {
+            4 => Protocol::V4,
+            5 => Protocol::V5,
+            level => return Err(Error::InvalidProtocolLevel(level)),
+        };
+        let flags = read_u8(&mut bytes)?;
+        let keep_alive = read_u16(&mut bytes)?;
+        skip_properties(protocol, &mut bytes)?;
+        let client_id = read_mqtt_string(&mut bytes)?;
+        // last will is not used by this crate, skip it
+        if flags & 0x04 != 0 {
+            skip_properties(protocol, &mut bytes)?;
+            read_mqtt_string(&mut bytes)?;
+            read_mqtt_bytes(&mut bytes)?;
+        }
+        let username = if flags & 0x80 != 0 {
+            read_mqtt_string(&mut bytes)?
+        } else {
+            String::new()
+        };
+        let password = if flags & 0x40 != 0 {
+            read_mqtt_string(&mut bytes)?
+        } else {
+            String::new()
+        };
+        let login = if flags & 0xC0 != 0 {
+            Some(Login { username, password })
+        } else {
+            None
+        };
+        Ok(Connect {
+            protocol,
+            keep_alive,
+            client_id,
+            clean_session: flags & 0x02 != 0,
+            login,
+        })
+    }
+}
+
+impl ConnAck {
+    pub fn write(&self, protocol: Protocol, buffer: &mut BytesMut) -> Result<usize, Error> {
+        let len = 2 + properties_len(protocol);
+        buffer.put_u8(0x20);
+        let count = write_remaining_length(buffer, len)?;
+        buffer.put_u8(self.session_present as u8);
+        buffer.put_u8(self.code);
+        write_properties(protocol, buffer);
+        Ok(1 + count + len)
+    }
+
+    fn read(protocol: Protocol, mut bytes: Bytes) -> Result<ConnAck, Error> {
+        let flags = read_u8(&mut bytes)?;
+        let code = read_u8(&mut bytes)?;
+        skip_properties(protocol, &mut bytes)?;
+        Ok(ConnAck {
+            session_present: flags & 0x01 != 0,
+            code,
+        })
+    }
+}
+
 impl Publish {
     pub fn new<S: Into<String>, P: Into<Vec<u8>>>(topic: S, qos: QoS, payload: P) -> Publish {
         Publish {
@@ -37,17 +435,17 @@ impl Publish {
             payload: Bytes::from(payload.into()),
         }
     }
-    fn len(&self) -> usize {
+    fn len(&self, protocol: Protocol) -> usize {
         let mut len = 2 + self.topic.len();
         if self.qos != QoS::AtMostOnce {
             len += 2;
         }
-
+        len += properties_len(protocol);
         len += self.payload.len();
         len
     }
-    pub fn write(&self, buffer: &mut BytesMut) -> Result<usize, Error> {
-        let len = self.len();
+    pub fn write(&self, protocol: Protocol, buffer: &mut BytesMut) -> Result<usize, Error> {
+        let len = self.len(protocol);
         // reserve for maximum possible fixed header
         buffer.reserve(5 + len);
 
@@ -62,10 +460,34 @@ impl Publish {
             }
             buffer.put_u16(pkid);
         }
+        write_properties(protocol, buffer);
 
         buffer.extend_from_slice(&self.payload);
         Ok(1 + count + len)
     }
+
+    fn read(protocol: Protocol, byte1: u8, mut bytes: Bytes) -> Result<Publish, Error> {
+        let qos = qos((byte1 & 0b0110) >> 1)?;
+        let topic = read_topic(&mut bytes)?;
+        let pkid = if qos != QoS::AtMostOnce {
+            let pkid = read_u16(&mut bytes)?;
+            if pkid == 0 {
+                return Err(Error::PacketIdZero);
+            }
+            pkid
+        } else {
+            0
+        };
+        skip_properties(protocol, &mut bytes)?;
+        Ok(Publish {
+            qos,
+            pkid,
+            topic,
+            payload: bytes,
+            dup: byte1 & 0b1000 != 0,
+            retain: byte1 & 0b0001 != 0,
+        })
+    }
 }
 
 impl Debug for Publish {
@@ -82,6 +504,191 @@ impl Debug for Publish {
     }
 }
 
+impl Subscribe {
+    fn len(&self, protocol: Protocol) -> usize {
+        let filters: usize = self.filters.iter().map(|f| 2 + f.path.len() + 1).sum();
+        2 + properties_len(protocol) + filters
+    }
+
+    pub fn write(&self, protocol: Protocol, buffer: &mut BytesMut) -> Result<usize, Error> {
+        if self.pkid == 0 {
+            return Err(Error::PacketIdZero);
+        }
+        let len = self.len(protocol);
+        buffer.reserve(5 + len);
+
+        buffer.put_u8(0x82);
+        let count = write_remaining_length(buffer, len)?;
+        buffer.put_u16(self.pkid);
+        write_properties(protocol, buffer);
+        for filter in &self.filters {
+            write_mqtt_string(buffer, &filter.path);
+            buffer.put_u8(filter.qos as u8);
+        }
+        Ok(1 + count + len)
+    }
+
+    fn read(protocol: Protocol, mut bytes: Bytes) -> Result<Subscribe, Error> {
+        let pkid = read_u16(&mut bytes)?;
+        skip_properties(protocol, &mut bytes)?;
+        let mut filters = Vec::new();
+        while bytes.has_remaining() {
+            let path = read_topic(&mut bytes)?;
+            let options = read_u8(&mut bytes)?;
+            filters.push(SubscribeFilter {
+                path,
+                qos: qos(options & 0b0011)?,
+            });
+        }
+        if filters.is_empty() {
+            return Err(Error::MalformedPacket);
+        }
+        Ok(Subscribe { pkid, filters })
+    }
+}
+
+impl SubAck {
+    pub fn write(&self, protocol: Protocol, buffer: &mut BytesMut) -> Result<usize, Error> {
+        let len = 2 + properties_len(protocol) + self.return_codes.len();
+        buffer.reserve(5 + len);
+
+        buffer.put_u8(0x90);
+        let count = write_remaining_length(buffer, len)?;
+        buffer.put_u16(self.pkid);
+        write_properties(protocol, buffer);
+        for code in &self.return_codes {
+            buffer.put_u8(match code {
+                SubscribeReasonCode::Success(qos) => *qos as u8,
+                SubscribeReasonCode::Failure => 0x80,
+            });
+        }
+        Ok(1 + count + len)
+    }
+
+    fn read(protocol: Protocol, mut bytes: Bytes) -> Result<SubAck, Error> {
+        let pkid = read_u16(&mut bytes)?;
+        skip_properties(protocol, &mut bytes)?;
+        let mut return_codes = Vec::with_capacity(bytes.len());
+        for code in bytes.iter().copied() {
+            return_codes.push(match code {
+                0..=2 => SubscribeReasonCode::Success(qos(code)?),
+                0x80.. => SubscribeReasonCode::Failure,
+                _ => return Err(Error::MalformedPacket),
+            });
+        }
+        Ok(SubAck { pkid, return_codes })
+    }
+}
+
+impl Unsubscribe {
+    pub fn write(&self, protocol: Protocol, buffer: &mut BytesMut) -> Result<usize, Error> {
+        if self.pkid == 0 {
+            return Err(Error::PacketIdZero);
+        }
+        let topics: usize = self.topics.iter().map(|t| 2 + t.len()).sum();
+        let len = 2 + properties_len(protocol) + topics;
+        buffer.reserve(5 + len);
+
+        buffer.put_u8(0xA2);
+        let count = write_remaining_length(buffer, len)?;
+        buffer.put_u16(self.pkid);
+        write_properties(protocol, buffer);
+        for topic in &self.topics {
+            write_mqtt_string(buffer, topic);
+        }
+        Ok(1 + count + len)
+    }
+
+    fn read(protocol: Protocol, mut bytes: Bytes) -> Result<Unsubscribe, Error> {
+        let pkid = read_u16(&mut bytes)?;
+        skip_properties(protocol, &mut bytes)?;
+        let mut topics = Vec::new();
+        while bytes.has_remaining() {
+            topics.push(read_topic(&mut bytes)?);
+        }
+        if topics.is_empty() {
+            return Err(Error::MalformedPacket);
+        }
+        Ok(Unsubscribe { pkid, topics })
+    }
+}
+
+impl UnsubAck {
+    pub fn write(&self, protocol: Protocol, buffer: &mut BytesMut) -> Result<usize, Error> {
+        let len = 2 + properties_len(protocol);
+        buffer.put_u8(0xB0);
+        let count = write_remaining_length(buffer, len)?;
+        buffer.put_u16(self.pkid);
+        write_properties(protocol, buffer);
+        Ok(1 + count + len)
+    }
+
+    fn read(protocol: Protocol, mut bytes: Bytes) -> Result<UnsubAck, Error> {
+        let pkid = read_u16(&mut bytes)?;
+        // reason codes of 5.0 are ignored
+        skip_properties(protocol, &mut bytes)?;
+        Ok(UnsubAck { pkid })
+    }
+}
+
+fn qos(num: u8) -> Result<QoS, Error> {
+    match num {
+        0 => Ok(QoS::AtMostOnce),
+        1 => Ok(QoS::AtLeastOnce),
+        2 => Ok(QoS::ExactlyOnce),
+        _ => Err(Error::InvalidQoS(num)),
+    }
+}
+
+/// PUBACK, PUBREC, PUBREL and PUBCOMP, 5.0 allows to omit the reason code when it is success.
+fn write_pkid(buffer: &mut BytesMut, byte1: u8, pkid: u16) -> Result<usize, Error> {
+    if pkid == 0 {
+        return Err(Error::PacketIdZero);
+    }
+    buffer.put_u8(byte1);
+    buffer.put_u8(2);
+    buffer.put_u16(pkid);
+    Ok(4)
+}
+
+fn read_pkid(mut bytes: Bytes) -> Result<u16, Error> {
+    // reason code and properties of 5.0 are ignored
+    match read_u16(&mut bytes)? {
+        0 => Err(Error::PacketIdZero),
+        pkid => Ok(pkid),
+    }
+}
+
+fn write_empty(buffer: &mut BytesMut, byte1: u8) -> Result<usize, Error> {
+    buffer.put_u8(byte1);
+    buffer.put_u8(0);
+    Ok(2)
+}
+
+fn properties_len(protocol: Protocol) -> usize {
+    match protocol {
+        Protocol::V4 => 0,
+        Protocol::V5 => 1,
+    }
+}
+
+fn write_properties(protocol: Protocol, buffer: &mut BytesMut) {
+    if protocol == Protocol::V5 {
+        buffer.put_u8(0);
+    }
+}
+
+fn skip_properties(protocol: Protocol, bytes: &mut Bytes) -> Result<(), Error> {
+    if protocol == Protocol::V5 && bytes.has_remaining() {
+        let (len, count) = read_remaining_length(bytes).map_err(|_| Error::MalformedPacket)?;
+        if count + len > bytes.len() {
+            return Err(Error::MalformedPacket);
+        }
+        bytes.advance(count + len);
+    }
+    Ok(())
+}
+
 fn write_mqtt_string(stream: &mut BytesMut, string: &str) {
     write_mqtt_bytes(stream, string.as_bytes());
 }
@@ -92,7 +699,7 @@ fn write_mqtt_bytes(stream: &mut BytesMut, bytes: &[u8]) {
 }
 
 fn write_remaining_length(stream: &mut BytesMut, len: usize) -> Result<usize, Error> {
-    if len > 268_435_455 {
+    if len > MAX_REMAINING_LENGTH {
         return Err(Error::PayloadTooLong);
     }
 
@@ -113,4 +720,176 @@ fn write_remaining_length(stream: &mut BytesMut, len: usize) -> Result<usize, Er
     }
 
     Ok(count)
-}
\ No newline at end of file
+}
+
+fn read_u8(stream: &mut Bytes) -> Result<u8, Error> {
+    if !stream.has_remaining() {
+        return Err(Error::MalformedPacket);
+    }
+    Ok(stream.get_u8())
+}
+
+fn read_u16(stream: &mut Bytes) -> Result<u16, Error> {
+    if stream.len() < 2 {
+        return Err(Error::MalformedPacket);
+    }
+    Ok(stream.get_u16())
+}
+
+fn read_mqtt_bytes(stream: &mut Bytes) -> Result<Bytes, Error> {
+    let len = read_u16(stream)? as usize;
+    if len > stream.len() {
+        return Err(Error::MalformedPacket);
+    }
+    Ok(stream.split_to(len))
+}
+
+fn read_mqtt_string(stream: &mut Bytes) -> Result<String, Error> {
+    let bytes = read_mqtt_bytes(stream)?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| Error::TopicNotUtf8)
+}
+
+fn read_topic(stream: &mut Bytes) -> Result<String, Error> {
+    read_mqtt_string(stream).map_err(|e| match e {
+        Error::MalformedPacket => Error::TruncatedTopic,
+        e => e,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packets(protocol: Protocol) -> Vec<Packet> {
+        let mut publish = Publish::new("plant/1/setpoint", QoS::AtLeastOnce, vec![7; 300]);
+        publish.pkid = 10;
+        publish.retain = true;
+        publish.dup = true;
+        vec![
+            Packet::Connect(Connect {
+                protocol,
+                keep_alive: 30,
+                client_id: "client".to_string(),
+                clean_session: true,
+                login: Some(Login {
+                    username: "user".to_string(),
+                    password: "pass".to_string(),
+                }),
+            }),
+            Packet::ConnAck(ConnAck {
+                session_present: true,
+                code: 0,
+            }),
+            Packet::Publish(Publish::new("a/b", QoS::AtMostOnce, b"hello".to_vec())),
+            Packet::Publish(publish),
+            Packet::PubAck(PubAck { pkid: 10 }),
+            Packet::PubRec(PubRec { pkid: 11 }),
+            Packet::PubRel(PubRel { pkid: 11 }),
+            Packet::PubComp(PubComp { pkid: 11 }),
+            Packet::Subscribe(Subscribe {
+                pkid: 1,
+                filters: vec![
+                    SubscribeFilter {
+                        path: "a/+".to_string(),
+                        qos: QoS::AtMostOnce,
+                    },
+                    SubscribeFilter {
+                        path: "b/#".to_string(),
+                        qos: QoS::ExactlyOnce,
+                    },
+                ],
+            }),
+            Packet::SubAck(SubAck {
+                pkid: 1,
+                return_codes: vec![
+                    SubscribeReasonCode::Success(QoS::AtMostOnce),
+                    SubscribeReasonCode::Failure,
+                ],
+            }),
+            Packet::Unsubscribe(Unsubscribe {
+                pkid: 2,
+                topics: vec!["a/+".to_string()],
+            }),
+            Packet::UnsubAck(UnsubAck { pkid: 2 }),
+            Packet::PingReq,
+            Packet::PingResp,
+            Packet::Disconnect,
+        ]
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for protocol in [Protocol::V4, Protocol::V5] {
+            for packet in packets(protocol) {
+                let mut buffer = BytesMut::new();
+                let len = packet.write(protocol, &mut buffer).unwrap();
+                assert_eq!(len, buffer.len());
+                assert_eq!(read(protocol, buffer.freeze()), Ok(packet));
+            }
+        }
+    }
+
+    #[test]
+    fn test_remaining_length() {
+        for len in [0, 127, 128, 16_383, 16_384, 2_097_151, 2_097_152, MAX_REMAINING_LENGTH] {
+            let mut buffer = BytesMut::new();
+            let count = write_remaining_length(&mut buffer, len).unwrap();
+            assert_eq!(read_remaining_length(&buffer), Ok((len, count)));
+        }
+        let mut buffer = BytesMut::new();
+        assert_eq!(write_remaining_length(&mut buffer, MAX_REMAINING_LENGTH + 1), Err(Error::PayloadTooLong));
+        assert_eq!(read_remaining_length(&[0xFF, 0xFF, 0xFF, 0xFF, 0x01]), Err(Error::MalformedRemainingLength));
+        assert_eq!(read_remaining_length(&[0xFF, 0xFF, 0xFF, 0xFF]), Err(Error::MalformedRemainingLength));
+        assert_eq!(read_remaining_length(&[0xFF, 0xFF]), Err(Error::InsufficientBytes(1)));
+    }
+
+    #[test]
+    fn test_partial_and_multiple_frames() {
+        let protocol = Protocol::V4;
+        let expected = packets(protocol);
+        let mut stream = BytesMut::new();
+        for packet in &expected {
+            packet.write(protocol, &mut stream).unwrap();
+        }
+        // all frames in one message
+        let mut decoder = PacketDecoder::new(protocol);
+        assert_eq!(decoder.push(&stream), Ok(expected.clone()));
+        assert_eq!(decoder.buffered(), 0);
+        // frames split across messages
+        let mut decoder = PacketDecoder::new(protocol);
+        let mut decoded = Vec::new();
+        for chunk in stream.chunks(7) {
+            decoded.extend(decoder.push(chunk).unwrap());
+        }
+        assert_eq!(decoded, expected);
+        assert_eq!(decoder.buffered(), 0);
+        // half a frame stays buffered
+        let mut decoder = PacketDecoder::new(protocol);
+        assert_eq!(decoder.push(&stream[..3]), Ok(vec![]));
+        assert_eq!(decoder.buffered(), 3);
+    }
+
+    #[test]
+    fn test_malformed() {
+        let protocol = Protocol::V4;
+        // publish with qos bits 11
+        let frame = Bytes::from_static(&[0x36, 0x05, 0x00, 0x01, b'a', 0x00, 0x01]);
+        assert_eq!(read(protocol, frame), Err(Error::InvalidQoS(3)));
+        // topic length says 10, only 1 byte follows
+        let frame = Bytes::from_static(&[0x30, 0x03, 0x00, 0x0A, b'a']);
+        assert_eq!(read(protocol, frame), Err(Error::TruncatedTopic));
+        let frame = Bytes::from_static(&[0x30, 0x04, 0x00, 0x02, 0xC3, 0x28]);
+        assert_eq!(read(protocol, frame), Err(Error::TopicNotUtf8));
+        let frame = Bytes::from_static(&[0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]);
+        assert_eq!(read(protocol, frame), Err(Error::MalformedRemainingLength));
+        let frame = Bytes::from_static(&[0x30, 0x05, 0x00]);
+        assert_eq!(read(protocol, frame), Err(Error::InsufficientBytes(4)));
+        let frame = Bytes::from_static(&[0xF0, 0x00]);
+        assert_eq!(read(protocol, frame), Err(Error::InvalidPacketType(15)));
+
+        let mut decoder = PacketDecoder::new(protocol);
+        decoder.set_max_packet_size(16);
+        assert_eq!(decoder.push(&[0x30, 0x20]), Err(Error::PacketTooLarge(32)));
+        assert_eq!(decoder.buffered(), 0);
+    }
+}
@@ -0,0 +1,875 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use gloo_utils::window;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{CloseEvent, ErrorEvent, Event, MessageEvent, WebSocket};
+use yew::platform::spawn_local;
+use yew::platform::time::sleep;
+use yew::Callback;
+
+use crate::codec::{self, Codec, CodecError, Codecs};
+use crate::mqtt::{self, MqttOptions, MqttState, StateError};
+use crate::publish::{ConnAck, PacketDecoder, Protocol, Publish, QoS, SubAck, UnsubAck};
+
+pub fn socket_send(socket: &WebSocket, topic: String, payload: Vec<u8>) -> Result<(), io::Error> {
+    let msg = Publish::new(topic, QoS::AtMostOnce, payload);
+    let mut write = BytesMut::new();
+    if let Err(e) = msg.write(Protocol::V4, &mut write) {
+        log::warn!("!!Failed to write msg: {:?}", msg);
+        Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))
+    } else {
+        match socket.send_with_u8_array(write.as_ref()) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                log::warn!("!!Error when mqtt sending: {:?}", e);
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, format!("{:?}", e)))
+            }
+        }
+    }
+}
+
+fn create_ws_socket(
+    url: &str,
+    backend: &str,
+    on_open: &JsValue,
+    on_message: &JsValue,
+    on_error: &JsValue,
+    on_close: &JsValue,
+) -> Option<WebSocket> {
+    let backend = backend.trim().to_string();
+    if let Ok(protocol) = window().location().protocol() {
+        let proto = if protocol.starts_with("https") {
+            String::from("wss")
+        } else {
+            String::from("ws")
+        };
+        let backend_now = if !backend.is_empty() {
+            backend.replace("https://", "").replace("http://", "")
+        } else if let Ok(host) = window().location().host() {
+            host
+        } else {
+            return None;
+        };
+
+        let ws_uri = proto + "://" + &backend_now + url;
+        log::debug!("Connect to web socket server: {}", ws_uri);
+        if let Ok(socket) = WebSocket::new(&ws_uri) {
+            socket.set_binary_type(web_sys::BinaryType::Arraybuffer);
+            socket.set_onopen(Some(on_open.unchecked_ref()));
+            socket.set_onmessage(Some(on_message.unchecked_ref()));
+            socket.set_onerror(Some(on_error.unchecked_ref()));
+            socket.set_onclose(Some(on_close.unchecked_ref()));
+            return Some(socket);
+        }
+    }
+    None
+}
+
+/// State of a `MySocket` connection, reported to `on_state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConnectionState {
+    Connecting,
+    Open,
+    /// Waiting before the given reconnection attempt, counted from 1
+    Reconnecting(u32),
+    #[default]
+    Closed,
+}
+
+/// How `MySocket` reconnects after losing the connection, with exponential backoff.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    initial_delay: Duration,
+    max_delay: Duration,
+    jitter: f64,
+    max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    pub fn set_initial_delay(&mut self, delay: Duration) -> &mut Self {
+        self.initial_delay = delay;
+        self
+    }
+
+    pub fn set_max_delay(&mut self, delay: Duration) -> &mut Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// Fraction of the delay randomly added or removed, between 0 and 1.
+    pub fn set_jitter(&mut self, jitter: f64) -> &mut Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// None retries forever.
+    pub fn set_max_attempts(&mut self, max_attempts: Option<u32>) -> &mut Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn allows(&self, attempt: u32) -> bool {
+        self.max_attempts.map_or(true, |max| attempt <= max)
+    }
+
+    /// Delay before `attempt`, counted from 1, `random` between 0 and 1 applies the jitter.
+    pub fn delay(&self, attempt: u32, random: f64) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let base = self.initial_delay.saturating_mul(factor).min(self.max_delay);
+        let jitter = 1.0 + self.jitter * (2.0 * random - 1.0);
+        base.mul_f64(jitter.max(0.0)).min(self.max_delay)
+    }
+}
+
+/// What `OutboundQueue` does with a message when it is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Discard the message queued first to make room
+    #[default]
+    DropOldest,
+    /// Discard the message queued last to make room
+    DropNewest,
+    /// Keep the queue as it is, the new message is not sent
+    Reject,
+}
+
+/// Bounded buffer of encoded messages sent while `MySocket` is disconnected,
+/// flushed in order once the connection is back.
+#[derive(Debug, Clone)]
+pub struct OutboundQueue {
+    capacity: usize,
+    overflow: OverflowPolicy,
+    messages: VecDeque<(String, Vec<u8>)>,
+}
+
+impl OutboundQueue {
+    pub fn new(capacity: usize, overflow: OverflowPolicy) -> Self {
+        Self {
+            capacity,
+            overflow,
+            messages: VecDeque::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn overflow(&self) -> OverflowPolicy {
+        self.overflow
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Returns false if the message was rejected.
+    pub fn push(&mut self, topic: String, payload: Vec<u8>) -> bool {
+        if self.capacity == 0 {
+            return false;
+        }
+        if self.messages.len() >= self.capacity {
+            match self.overflow {
+                OverflowPolicy::DropOldest => {
+                    self.messages.pop_front();
+                }
+                OverflowPolicy::DropNewest => {
+                    self.messages.pop_back();
+                }
+                OverflowPolicy::Reject => return false,
+            }
+            log::warn!("!!Outbound queue is full, a message is dropped");
+        }
+        self.messages.push_back((topic, payload));
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<(String, Vec<u8>)> {
+        self.messages.pop_front()
+    }
+
+    pub fn clear(&mut self) {
+        self.messages.clear();
+    }
+}
+
+type SharedQueue = Rc<RefCell<Option<OutboundQueue>>>;
+
+/// Outcome of a QoS 1 or 2 publish, identified by its packet id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// PUBACK or PUBCOMP received
+    Acknowledged(u16),
+    /// The socket was closed, or the connection lost without reconnecting, before the broker
+    /// acknowledged it
+    Abandoned(u16),
+}
+
+/// Callbacks of a `MySocket` running the MQTT protocol.
+#[derive(Clone, Default)]
+pub struct MqttCallbacks {
+    /// The broker accepted CONNECT, subscribe here.
+    pub on_connect: Callback<ConnAck>,
+    /// An inbound PUBLISH, as topic and payload.
+    pub on_message: Callback<(String, Bytes)>,
+    pub on_subscribe: Callback<SubAck>,
+    pub on_unsubscribe: Callback<UnsubAck>,
+    /// Result of every publish sent with QoS 1 or 2.
+    pub on_delivery: Callback<Delivery>,
+    pub on_close: Callback<CloseEvent>,
+}
+
+struct MqttSession {
+    state: MqttState,
+    decoder: PacketDecoder,
+    socket: Option<WebSocket>,
+    callbacks: MqttCallbacks,
+    /// Increased by every connect, stale keep alive loops stop when it changes
+    epoch: u32,
+    queue: SharedQueue,
+}
+
+impl MqttSession {
+    /// Publish the messages queued while disconnected, in order.
+    fn flush_queue(&mut self) {
+        if !self.state.is_connected() {
+            return;
+        }
+        let queue = self.queue.clone();
+        let mut queue = queue.borrow_mut();
+        while let Some((topic, payload)) = queue.as_mut().and_then(|q| q.pop()) {
+            if let Err(e) = self.state.publish(Publish::new(topic, QoS::AtMostOnce, payload)) {
+                log::warn!("!!Failed to publish queued message, {}", e);
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(bytes) = self.state.take_outgoing() {
+            if let Some(socket) = &self.socket {
+                if let Err(e) = socket.send_with_u8_array(&bytes) {
+                    log::warn!("!!Error when mqtt sending: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Whether `event` comes from `socket`, events of a replaced socket are ignored.
+fn is_target(socket: Option<&WebSocket>, event: &Event) -> bool {
+    match (socket, event.target()) {
+        (Some(socket), Some(target)) => {
+            let socket: &JsValue = socket.as_ref();
+            let target: &JsValue = target.as_ref();
+            socket == target
+        }
+        _ => false,
+    }
+}
+
+fn mqtt_open(session: &Rc<RefCell<MqttSession>>) {
+    let mut s = session.borrow_mut();
+    if let Err(e) = s.state.connect() {
+        log::warn!("!!Failed to write CONNECT: {}", e);
+    }
+    s.flush();
+}
+
+fn mqtt_message(session: &Rc<RefCell<MqttSession>>, data: &[u8]) {
+    let (results, callbacks) = {
+        let mut s = session.borrow_mut();
+        let results = match s.decoder.push(data) {
+            Ok(packets) => packets
+                .into_iter()
+                .filter_map(|packet| s.state.handle_incoming(packet).transpose())
+                .collect(),
+            Err(e) => vec![Err(StateError::from(e))],
+        };
+        s.flush_queue();
+        s.flush();
+        (results, s.callbacks.clone())
+    };
+    // the borrow is released, callbacks may use the socket again
+    for result in results {
+        match result {
+            Ok(mqtt::Event::Connected(ack)) => {
+                spawn_keep_alive(session);
+                callbacks.on_connect.emit(ack);
+            }
+            Ok(mqtt::Event::Publish(p)) => callbacks.on_message.emit((p.topic, p.payload)),
+            Ok(mqtt::Event::SubAck(ack)) => callbacks.on_subscribe.emit(ack),
+            Ok(mqtt::Event::UnsubAck(ack)) => callbacks.on_unsubscribe.emit(ack),
+            Ok(mqtt::Event::PubAck(ack)) => callbacks.on_delivery.emit(Delivery::Acknowledged(ack.pkid)),
+            Ok(mqtt::Event::PubComp(comp)) => callbacks.on_delivery.emit(Delivery::Acknowledged(comp.pkid)),
+            Ok(mqtt::Event::PingResp) => {}
+            Err(e) => {
+                log::warn!("!!Mqtt error: {}", e);
+                if let StateError::ConnectionRefused(_) | StateError::Packet(_) = e {
+                    if let Some(socket) = &session.borrow().socket {
+                        let _ = socket.close();
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn spawn_keep_alive(session: &Rc<RefCell<MqttSession>>) {
+    let (secs, epoch) = {
+        let s = session.borrow();
+        (s.state.options().keep_alive(), s.epoch)
+    };
+    if secs == 0 {
+        return;
+    }
+    let weak: Weak<RefCell<MqttSession>> = Rc::downgrade(session);
+    spawn_local(async move {
+        loop {
+            sleep(Duration::from_secs(secs as u64)).await;
+            let Some(session) = weak.upgrade() else {
+                break;
+            };
+            let mut s = session.borrow_mut();
+            if s.epoch != epoch || !s.state.is_connected() {
+                break;
+            }
+            match s.state.ping() {
+                Ok(_) => s.flush(),
+                Err(e) => {
+                    log::warn!("!!Mqtt keep alive failed: {}", e);
+                    if let Some(socket) = &s.socket {
+                        let _ = socket.close();
+                    }
+                    break;
+                }
+            }
+        }
+    });
+}
+
+#[derive(Default)]
+struct Reconnect {
+    policy: Option<ReconnectPolicy>,
+    on_state: Callback<ConnectionState>,
+    state: ConnectionState,
+    attempt: u32,
+    /// Increased by connect and close, a pending reconnection is dropped when it changes
+    epoch: u32,
+}
+
+type Listener = Closure<dyn Fn(Event)>;
+
+struct SocketInner {
+    url: String,
+    backend: String,
+    socket: RefCell<Option<WebSocket>>,
+    on_open: Closure<dyn Fn(JsValue)>,
+    on_message: Closure<dyn Fn(MessageEvent)>,
+    on_error: Closure<dyn Fn(ErrorEvent)>,
+    on_close: Closure<dyn Fn(CloseEvent)>,
+    /// Listeners added next to the handlers above, they follow the connection state
+    on_opened: RefCell<Option<Listener>>,
+    on_closed: RefCell<Option<Listener>>,
+    session: Option<Rc<RefCell<MqttSession>>>,
+    reconnect: RefCell<Reconnect>,
+    queue: SharedQueue,
+    codecs: RefCell<Codecs>,
+}
+
+fn set_state(inner: &SocketInner, state: ConnectionState) {
+    let callback = {
+        let mut r = inner.reconnect.borrow_mut();
+        if r.state == state {
+            return;
+        }
+        r.state = state;
+        r.on_state.clone()
+    };
+    callback.emit(state);
+}
+
+fn open_socket(inner: &Rc<SocketInner>) -> bool {
+    let socket = create_ws_socket(&inner.url, &inner.backend, inner.on_open.as_ref(),
+                                  inner.on_message.as_ref(), inner.on_error.as_ref(),
+                                  inner.on_close.as_ref());
+    if let Some(s) = inner.socket.replace(socket.clone()) {
+        s.close().unwrap();
+    }
+    if let Some(session) = &inner.session {
+        let mut s = session.borrow_mut();
+        s.state.connection_lost();
+        s.decoder.clear();
+        s.socket = socket.clone();
+        s.epoch = s.epoch.wrapping_add(1);
+    }
+    match socket {
+        Some(socket) => {
+            add_listeners(inner, &socket);
+            set_state(inner, ConnectionState::Connecting);
+            true
+        }
+        None => false,
+    }
+}
+
+fn add_listeners(inner: &Rc<SocketInner>, socket: &WebSocket) {
+    let mut on_opened = inner.on_opened.borrow_mut();
+    let on_opened = on_opened.get_or_insert_with(|| {
+        let weak = Rc::downgrade(inner);
+        Closure::wrap(Box::new(move |e: Event| {
+            if let Some(inner) = weak.upgrade() {
+                if is_target(inner.socket.borrow().as_ref(), &e) {
+                    inner.reconnect.borrow_mut().attempt = 0;
+                    // sockets speaking MQTT flush their queue after CONNACK
+                    if inner.session.is_none() {
+                        flush_raw_queue(&inner);
+                    }
+                    set_state(&inner, ConnectionState::Open);
+                }
+            }
+        }) as Box<dyn Fn(Event)>)
+    });
+    let mut on_closed = inner.on_closed.borrow_mut();
+    let on_closed = on_closed.get_or_insert_with(|| {
+        let weak = Rc::downgrade(inner);
+        Closure::wrap(Box::new(move |e: Event| {
+            if let Some(inner) = weak.upgrade() {
+                if is_target(inner.socket.borrow().as_ref(), &e) {
+                    connection_lost(&inner);
+                }
+            }
+        }) as Box<dyn Fn(Event)>)
+    });
+    let _ = socket.add_event_listener_with_callback("open", on_opened.as_ref().unchecked_ref());
+    // an error is always followed by close, listening to close is enough
+    let _ = socket.add_event_listener_with_callback("close", on_closed.as_ref().unchecked_ref());
+}
+
+/// Detach the handlers and listeners of `inner` from `socket`, its events arriving after the
+/// closures are dropped would call freed closures.
+fn remove_listeners(inner: &SocketInner, socket: &WebSocket) {
+    socket.set_onopen(None);
+    socket.set_onmessage(None);
+    socket.set_onerror(None);
+    socket.set_onclose(None);
+    if let Some(on_opened) = inner.on_opened.borrow().as_ref() {
+        let _ = socket.remove_event_listener_with_callback("open", on_opened.as_ref().unchecked_ref());
+    }
+    if let Some(on_closed) = inner.on_closed.borrow().as_ref() {
+        let _ = socket.remove_event_listener_with_callback("close", on_closed.as_ref().unchecked_ref());
+    }
+}
+
+fn flush_raw_queue(inner: &SocketInner) {
+    let socket = inner.socket.borrow();
+    let Some(socket) = socket.as_ref() else {
+        return;
+    };
+    let mut queue = inner.queue.borrow_mut();
+    while let Some((topic, payload)) = queue.as_mut().and_then(|q| q.pop()) {
+        if let Err(e) = socket_send(socket, topic, payload) {
+            log::warn!("!!Failed to send queued message, {:?}", e);
+        }
+    }
+}
+
+/// Report the unacknowledged QoS 1 and 2 publishes as `Delivery::Abandoned`.
+fn abandon_inflight(inner: &SocketInner) {
+    let Some(session) = &inner.session else {
+        return;
+    };
+    let (abandoned, callback) = {
+        let mut s = session.borrow_mut();
+        (s.state.abandon_inflight(), s.callbacks.on_delivery.clone())
+    };
+    for pkid in abandoned {
+        callback.emit(Delivery::Abandoned(pkid));
+    }
+}
+
+fn connection_lost(inner: &Rc<SocketInner>) {
+    let next = {
+        let mut r = inner.reconnect.borrow_mut();
+        let attempt = r.attempt + 1;
+        match &r.policy {
+            Some(policy) if policy.allows(attempt) => {
+                let delay = policy.delay(attempt, js_sys::Math::random());
+                r.attempt = attempt;
+                Some((attempt, delay, r.epoch))
+            }
+            _ => None,
+        }
+    };
+    let Some((attempt, delay, epoch)) = next else {
+        // no reconnect to send them again
+        abandon_inflight(inner);
+        set_state(inner, ConnectionState::Closed);
+        return;
+    };
+    set_state(inner, ConnectionState::Reconnecting(attempt));
+    log::debug!("Reconnect to web socket server in {:?}, attempt {}", delay, attempt);
+    let weak = Rc::downgrade(inner);
+    spawn_local(async move {
+        sleep(delay).await;
+        if let Some(inner) = weak.upgrade() {
+            if inner.reconnect.borrow().epoch == epoch && !open_socket(&inner) {
+                connection_lost(&inner);
+            }
+        }
+    });
+}
+
+pub struct MySocket {
+    inner: Rc<SocketInner>,
+}
+
+impl MySocket {
+    pub fn new(
+        url: String,
+        backend: String,
+        on_open: Closure<dyn Fn(JsValue)>,
+        on_message: Closure<dyn Fn(MessageEvent)>,
+        on_error: Closure<dyn Fn(ErrorEvent)>,
+        on_close: Closure<dyn Fn(CloseEvent)>,
+    ) -> Self {
+        Self::create(url, backend, on_open, on_message, on_error, on_close, None)
+    }
+
+    /// A socket speaking MQTT: CONNECT is sent when the socket opens, PINGREQ every keep alive
+    /// interval and inbound packets are decoded into `callbacks`.
+    pub fn new_mqtt(url: String, backend: String, options: MqttOptions, callbacks: MqttCallbacks) -> Self {
+        let decoder = PacketDecoder::new(options.protocol());
+        let session = Rc::new(RefCell::new(MqttSession {
+            state: MqttState::new(options),
+            decoder,
+            socket: None,
+            callbacks,
+            epoch: 0,
+            queue: SharedQueue::default(),
+        }));
+        let on_open = {
+            let session = session.clone();
+            Closure::wrap(Box::new(move |_: JsValue| mqtt_open(&session)) as Box<dyn Fn(JsValue)>)
+        };
+        let on_message = {
+            let session = session.clone();
+            Closure::wrap(Box::new(move |e: MessageEvent| {
+                if let Ok(buf) = e.data().dyn_into::<js_sys::ArrayBuffer>() {
+                    mqtt_message(&session, &js_sys::Uint8Array::new(&buf).to_vec());
+                }
+            }) as Box<dyn Fn(MessageEvent)>)
+        };
+        let on_error = Closure::wrap(Box::new(move |e: ErrorEvent| {
+            log::warn!("!!Web socket error: {:?}", e.message());
+        }) as Box<dyn Fn(ErrorEvent)>);
+        let on_close = {
+            let session = session.clone();
+            Closure::wrap(Box::new(move |e: CloseEvent| {
+                let callback = {
+                    let mut s = session.borrow_mut();
+                    if !is_target(s.socket.as_ref(), &e) {
+                        return;
+                    }
+                    s.state.connection_lost();
+                    s.callbacks.on_close.clone()
+                };
+                callback.emit(e);
+            }) as Box<dyn Fn(CloseEvent)>)
+        };
+        Self::create(url, backend, on_open, on_message, on_error, on_close, Some(session))
+    }
+
+    fn create(
+        url: String,
+        backend: String,
+        on_open: Closure<dyn Fn(JsValue)>,
+        on_message: Closure<dyn Fn(MessageEvent)>,
+        on_error: Closure<dyn Fn(ErrorEvent)>,
+        on_close: Closure<dyn Fn(CloseEvent)>,
+        session: Option<Rc<RefCell<MqttSession>>>,
+    ) -> Self {
+        // the session flushes the queue itself after CONNACK
+        let queue = session.as_ref().map_or_else(SharedQueue::default, |s| s.borrow().queue.clone());
+        let inner = SocketInner {
+            url,
+            backend,
+            socket: RefCell::new(None),
+            on_open,
+            on_message,
+            on_error,
+            on_close,
+            on_opened: RefCell::new(None),
+            on_closed: RefCell::new(None),
+            session,
+            reconnect: RefCell::new(Reconnect::default()),
+            queue,
+            codecs: RefCell::new(Codecs::default()),
+        };
+        Self { inner: Rc::new(inner) }
+    }
+
+    /// Reconnect automatically when the connection is lost, None turns it off.
+    /// Topics of sockets created by `new_mqtt` are subscribed again after reconnecting.
+    pub fn set_reconnect_policy(&mut self, policy: Option<ReconnectPolicy>) {
+        self.inner.reconnect.borrow_mut().policy = policy;
+    }
+
+    /// Notified on every change of `state`.
+    pub fn set_on_state(&mut self, on_state: Callback<ConnectionState>) {
+        self.inner.reconnect.borrow_mut().on_state = on_state;
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.inner.reconnect.borrow().state
+    }
+
+    /// Codec of the topics without their own, CBOR by default.
+    pub fn set_codec(&mut self, codec: Rc<dyn Codec>) {
+        self.inner.codecs.borrow_mut().set_default(codec);
+    }
+
+    /// Codec used for both sending and receiving on `topic`, None falls back to the socket's.
+    pub fn set_topic_codec(&mut self, topic: &str, codec: Option<Rc<dyn Codec>>) {
+        let mut codecs = self.inner.codecs.borrow_mut();
+        match codec {
+            Some(codec) => codecs.set_topic(topic, codec),
+            None => codecs.remove_topic(topic),
+        };
+    }
+
+    /// Decode an inbound payload with the codec of `topic`.
+    pub fn decode<T: DeserializeOwned>(&self, topic: &str, payload: &[u8]) -> Result<T, CodecError> {
+        let codec = self.inner.codecs.borrow().get(topic);
+        codec::decode(codec.as_ref(), payload)
+    }
+
+    fn encode<T: Serialize>(&self, topic: &str, value: &T) -> Option<Vec<u8>> {
+        let codec = self.inner.codecs.borrow().get(topic);
+        match codec::encode(codec.as_ref(), value) {
+            Ok(payload) => Some(payload),
+            Err(e) => {
+                log::warn!("!!Failed to encode payload for {}, {}", topic, e);
+                None
+            }
+        }
+    }
+
+    /// Keep messages of `send` while disconnected instead of losing them, None turns it off.
+    /// Messages already queued are dropped when the queue is replaced.
+    pub fn set_outbound_queue(&mut self, queue: Option<OutboundQueue>) {
+        *self.inner.queue.borrow_mut() = queue;
+    }
+
+    /// Number of messages waiting for the connection.
+    pub fn queue_depth(&self) -> usize {
+        self.inner.queue.borrow().as_ref().map_or(0, |q| q.len())
+    }
+
+    /// Unacknowledged QoS 1 and 2 publishes are reported as `Delivery::Abandoned`.
+    /// The handlers are detached first, the close event of the socket is not reported.
+    pub fn close(&mut self) {
+        {
+            let mut r = self.inner.reconnect.borrow_mut();
+            r.epoch = r.epoch.wrapping_add(1);
+        }
+        if let Some(session) = &self.inner.session {
+            let mut s = session.borrow_mut();
+            if let Err(e) = s.state.disconnect() {
+                log::warn!("!!Failed to write DISCONNECT: {}", e);
+            }
+            s.flush();
+        }
+        abandon_inflight(&self.inner);
+        if let Some(s) = self.inner.socket.take() {
+            remove_listeners(&self.inner, &s);
+            s.close().unwrap();
+        }
+        set_state(&self.inner, ConnectionState::Closed);
+    }
+
+    pub fn connect(&mut self) -> bool {
+        {
+            let mut r = self.inner.reconnect.borrow_mut();
+            r.epoch = r.epoch.wrapping_add(1);
+            r.attempt = 0;
+        }
+        open_socket(&self.inner)
+    }
+
+    /// Returns false if the message is lost, with an outbound queue it is kept while disconnected.
+    pub fn send<T>(&self, topic: &str, value: &T) -> bool
+    where
+        T: Serialize,
+    {
+        if !self.is_connected() && self.inner.queue.borrow().is_some() {
+            return match self.encode(topic, value) {
+                Some(payload) => self.inner.queue.borrow_mut().as_mut().unwrap().push(topic.to_string(), payload),
+                None => false,
+            };
+        }
+        if self.inner.socket.borrow().is_none() {
+            return false;
+        }
+        if self.inner.session.is_some() {
+            return self.publish(topic, value, QoS::AtMostOnce).is_some();
+        }
+        if let Some(payload) = self.encode(topic, value) {
+            if let Err(e) = socket_send(self.inner.socket.borrow().as_ref().unwrap(), topic.to_string(), payload) {
+                log::warn!("!!Failed to send REGISTER_TOPIC to server, {:?}", e);
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Publish with the given QoS, only for sockets created by `new_mqtt`.
+    /// Returns the packet id, zero for QoS 0, the outcome of QoS 1 and 2 is reported to `on_delivery`.
+    /// Publishes not acknowledged are sent again with the `dup` flag after reconnecting.
+    pub fn publish<T>(&self, topic: &str, value: &T, qos: QoS) -> Option<u16>
+    where
+        T: Serialize,
+    {
+        let session = self.inner.session.as_ref()?;
+        let payload = self.encode(topic, value)?;
+        let mut s = session.borrow_mut();
+        match s.state.publish(Publish::new(topic, qos, payload)) {
+            Ok(pkid) => {
+                s.flush();
+                Some(pkid)
+            }
+            Err(e) => {
+                log::warn!("!!Failed to publish to {}, {}", topic, e);
+                None
+            }
+        }
+    }
+
+    /// Subscribe to a topic filter, only for sockets created by `new_mqtt` after `on_connect`.
+    pub fn subscribe(&self, filter: &str, qos: QoS) -> bool {
+        self.with_session(|s| s.state.subscribe(filter, qos).map(|_| ()))
+    }
+
+    pub fn unsubscribe(&self, filter: &str) -> bool {
+        self.with_session(|s| s.state.unsubscribe(filter).map(|_| ()))
+    }
+
+    /// Filters acknowledged by the broker, they are restored after reconnecting.
+    pub fn subscriptions(&self) -> Vec<String> {
+        match &self.inner.session {
+            Some(session) => session.borrow().state.subscriptions().keys().cloned().collect(),
+            None => vec![],
+        }
+    }
+
+    /// For sockets created by `new_mqtt` this also requires the broker to have accepted CONNECT.
+    pub fn is_connected(&self) -> bool {
+        let open = matches!(self.inner.socket.borrow().as_ref(), Some(s) if s.ready_state() == 1);
+        match &self.inner.session {
+            Some(session) => open && session.borrow().state.is_connected(),
+            None => open,
+        }
+    }
+
+    fn with_session<F>(&self, f: F) -> bool
+    where
+        F: FnOnce(&mut MqttSession) -> Result<(), StateError>,
+    {
+        if let Some(session) = &self.inner.session {
+            let mut s = session.borrow_mut();
+            match f(&mut s) {
+                Ok(_) => {
+                    s.flush();
+                    return true;
+                }
+                Err(e) => log::warn!("!!Mqtt request failed: {}", e),
+            }
+        }
+        false
+    }
+}
+
+impl Drop for MySocket {
+    fn drop(&mut self) {
+        if let Some(s) = self.inner.socket.take() {
+            remove_listeners(&self.inner, &s);
+            let _ = s.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut policy = ReconnectPolicy::default();
+        policy
+            .set_initial_delay(Duration::from_millis(500))
+            .set_max_delay(Duration::from_secs(10))
+            .set_jitter(0.0)
+            .set_max_attempts(Some(8));
+        let delays: Vec<_> = (1..=7).map(|attempt| policy.delay(attempt, 0.5).as_millis()).collect();
+        assert_eq!(delays, vec![500, 1000, 2000, 4000, 8000, 10000, 10000]);
+        assert_eq!(policy.delay(u32::MAX, 0.5), Duration::from_secs(10));
+        assert!(policy.allows(8));
+        assert!(!policy.allows(9));
+
+        policy.set_jitter(0.5);
+        assert_eq!(policy.delay(2, 0.0), Duration::from_millis(500));
+        assert_eq!(policy.delay(2, 0.5), Duration::from_millis(1000));
+        assert_eq!(policy.delay(2, 1.0), Duration::from_millis(1500));
+        // jitter never goes above the maximum
+        assert_eq!(policy.delay(6, 1.0), Duration::from_secs(10));
+        assert!(ReconnectPolicy::default().allows(u32::MAX));
+    }
+
+    #[test]
+    fn test_outbound_queue() {
+        let topics = |q: &mut OutboundQueue| {
+            let mut topics = vec![];
+            while let Some((topic, _)) = q.pop() {
+                topics.push(topic);
+            }
+            topics
+        };
+        for (overflow, accepted, expected) in [
+            (OverflowPolicy::DropOldest, true, vec!["b", "c", "d"]),
+            (OverflowPolicy::DropNewest, true, vec!["a", "b", "d"]),
+            (OverflowPolicy::Reject, false, vec!["a", "b", "c"]),
+        ] {
+            let mut queue = OutboundQueue::new(3, overflow);
+            for topic in ["a", "b", "c"] {
+                assert!(queue.push(topic.to_string(), vec![]));
+            }
+            assert_eq!(queue.push("d".to_string(), vec![]), accepted);
+            assert_eq!(queue.len(), 3);
+            assert_eq!(topics(&mut queue), expected);
+            assert!(queue.is_empty());
+        }
+        assert!(!OutboundQueue::new(0, OverflowPolicy::DropOldest).push("a".to_string(), vec![]));
+    }
+}
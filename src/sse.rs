@@ -0,0 +1,368 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeSet;
+use std::rc::Rc;
+
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{Event, EventSource, EventSourceInit, MessageEvent};
+use yew::platform::spawn_local;
+use yew::platform::time::sleep;
+use yew::Callback;
+
+use crate::codec::{self, Codec, CodecError, Codecs, Json};
+use crate::fetch::encode_component;
+use crate::socket::{ConnectionState, ReconnectPolicy};
+
+/// Query parameter carrying the id of the last event received when reconnecting,
+/// `EventSource` can not send the `Last-Event-ID` header itself on a new connection.
+pub const PARAM_LAST_EVENT_ID: &str = "lastEventId";
+
+/// Type of the events sent without an `event:` field.
+pub const DEFAULT_EVENT: &str = "message";
+
+#[derive(Default)]
+struct Reconnect {
+    policy: Option<ReconnectPolicy>,
+    on_state: Callback<ConnectionState>,
+    state: ConnectionState,
+    attempt: u32,
+    /// Increased by connect and close, a pending reconnection is dropped when it changes
+    epoch: u32,
+}
+
+type Listener<E> = Closure<dyn Fn(E)>;
+
+struct SourceInner {
+    url: String,
+    with_credentials: Cell<bool>,
+    source: RefCell<Option<EventSource>>,
+    on_message: Callback<(String, Bytes)>,
+    /// Event types listened to
+    events: RefCell<BTreeSet<String>>,
+    on_event: RefCell<Option<Listener<MessageEvent>>>,
+    on_opened: RefCell<Option<Listener<Event>>>,
+    on_failed: RefCell<Option<Listener<Event>>>,
+    last_event_id: RefCell<Option<String>>,
+    reconnect: RefCell<Reconnect>,
+    codecs: RefCell<Codecs>,
+}
+
+fn is_source(inner: &SourceInner, e: &Event) -> bool {
+    let source = inner.source.borrow();
+    match (source.as_ref(), e.target()) {
+        (Some(source), Some(target)) => {
+            let source: &JsValue = source.as_ref();
+            let target: &JsValue = target.as_ref();
+            source == target
+        }
+        _ => false,
+    }
+}
+
+fn set_state(inner: &SourceInner, state: ConnectionState) {
+    let callback = {
+        let mut r = inner.reconnect.borrow_mut();
+        if r.state == state {
+            return;
+        }
+        r.state = state;
+        r.on_state.clone()
+    };
+    callback.emit(state);
+}
+
+/// `url` with the id of the last event received as query parameter.
+fn resume_url(url: &str, last_event_id: Option<&str>) -> String {
+    let Some(id) = last_event_id else {
+        return url.to_string();
+    };
+    let (base, fragment) = url.split_once('#').map_or((url, None), |(b, f)| (b, Some(f)));
+    let (path, query) = base.split_once('?').unwrap_or((base, ""));
+    let mut params: Vec<&str> = query
+        .split('&')
+        .filter(|p| !p.is_empty() && p.split('=').next() != Some(PARAM_LAST_EVENT_ID))
+        .collect();
+    let param = format!("{}={}", PARAM_LAST_EVENT_ID, encode_component(id));
+    params.push(&param);
+    let mut url = format!("{}?{}", path, params.join("&"));
+    if let Some(fragment) = fragment {
+        url.push('#');
+        url.push_str(fragment);
+    }
+    url
+}
+
+fn open_source(inner: &Rc<SourceInner>) -> bool {
+    let url = resume_url(&inner.url, inner.last_event_id.borrow().as_deref());
+    let init = EventSourceInit::new();
+    init.set_with_credentials(inner.with_credentials.get());
+    let source = match EventSource::new_with_event_source_init_dict(&url, &init) {
+        Ok(source) => source,
+        Err(e) => {
+            log::warn!("!!Failed to open event source {}, {:?}", url, e);
+            return false;
+        }
+    };
+    add_listeners(inner, &source);
+    if let Some(previous) = inner.source.replace(Some(source)) {
+        previous.close();
+    }
+    set_state(inner, ConnectionState::Connecting);
+    true
+}
+
+fn add_listeners(inner: &Rc<SourceInner>, source: &EventSource) {
+    let mut on_event = inner.on_event.borrow_mut();
+    let on_event = on_event.get_or_insert_with(|| {
+        let weak = Rc::downgrade(inner);
+        Closure::wrap(Box::new(move |e: MessageEvent| {
+            let Some(inner) = weak.upgrade() else {
+                return;
+            };
+            if !is_source(&inner, &e) {
+                return;
+            }
+            let id = e.last_event_id();
+            if !id.is_empty() {
+                *inner.last_event_id.borrow_mut() = Some(id);
+            }
+            let data = e.data().as_string().unwrap_or_default();
+            inner.on_message.emit((e.type_(), Bytes::from(data)));
+        }) as Box<dyn Fn(MessageEvent)>)
+    });
+    for event in inner.events.borrow().iter() {
+        let _ = source.add_event_listener_with_callback(event, on_event.as_ref().unchecked_ref());
+    }
+    let mut on_opened = inner.on_opened.borrow_mut();
+    let on_opened = on_opened.get_or_insert_with(|| {
+        let weak = Rc::downgrade(inner);
+        Closure::wrap(Box::new(move |e: Event| {
+            if let Some(inner) = weak.upgrade() {
+                if is_source(&inner, &e) {
+                    inner.reconnect.borrow_mut().attempt = 0;
+                    set_state(&inner, ConnectionState::Open);
+                }
+            }
+        }) as Box<dyn Fn(Event)>)
+    });
+    let mut on_failed = inner.on_failed.borrow_mut();
+    let on_failed = on_failed.get_or_insert_with(|| {
+        let weak = Rc::downgrade(inner);
+        Closure::wrap(Box::new(move |e: Event| {
+            if let Some(inner) = weak.upgrade() {
+                if is_source(&inner, &e) {
+                    connection_lost(&inner);
+                }
+            }
+        }) as Box<dyn Fn(Event)>)
+    });
+    let _ = source.add_event_listener_with_callback("open", on_opened.as_ref().unchecked_ref());
+    let _ = source.add_event_listener_with_callback("error", on_failed.as_ref().unchecked_ref());
+}
+
+fn connection_lost(inner: &Rc<SourceInner>) {
+    let next = {
+        let mut r = inner.reconnect.borrow_mut();
+        let attempt = r.attempt + 1;
+        match &r.policy {
+            Some(policy) if policy.allows(attempt) => {
+                let delay = policy.delay(attempt, js_sys::Math::random());
+                r.attempt = attempt;
+                Some((attempt, delay, r.epoch))
+            }
+            _ => None,
+        }
+    };
+    let Some((attempt, delay, epoch)) = next else {
+        // without a policy the browser retries by itself until the server refuses
+        let gave_up = inner.reconnect.borrow().policy.is_some();
+        let retrying = inner.source.borrow().as_ref().is_some_and(|s| s.ready_state() == EventSource::CONNECTING);
+        if retrying && !gave_up {
+            set_state(inner, ConnectionState::Connecting);
+        } else {
+            if let Some(source) = inner.source.take() {
+                source.close();
+            }
+            set_state(inner, ConnectionState::Closed);
+        }
+        return;
+    };
+    // reconnect with our own policy, sending the last event id
+    if let Some(source) = inner.source.borrow().as_ref() {
+        source.close();
+    }
+    set_state(inner, ConnectionState::Reconnecting(attempt));
+    log::debug!("Reconnect to event source in {:?}, attempt {}", delay, attempt);
+    let weak = Rc::downgrade(inner);
+    spawn_local(async move {
+        sleep(delay).await;
+        if let Some(inner) = weak.upgrade() {
+            if inner.reconnect.borrow().epoch == epoch && !open_source(&inner) {
+                connection_lost(&inner);
+            }
+        }
+    });
+}
+
+/// A Server-Sent Events client with the ergonomics of `MySocket`: the events reach
+/// `on_message` as event type and data, like topic and payload, and are decoded with `decode`.
+///
+/// ```ignore
+/// let on_message = ctx.link().callback(|(event, data): (String, Bytes)| Msg::Event(event, data));
+/// let mut source = MyEventSource::new("/api/v1/events".to_string(), on_message);
+/// source.add_event("alarm");
+/// source.set_reconnect_policy(Some(ReconnectPolicy::default()));
+/// source.connect();
+/// // in update
+/// let alarm: Alarm = self.source.decode(&event, &data)?;
+/// ```
+pub struct MyEventSource {
+    inner: Rc<SourceInner>,
+}
+
+impl MyEventSource {
+    /// Receives the events without type, see `add_event` for the others.
+    pub fn new(url: String, on_message: Callback<(String, Bytes)>) -> Self {
+        let inner = SourceInner {
+            url,
+            with_credentials: Cell::new(false),
+            source: RefCell::new(None),
+            on_message,
+            events: RefCell::new(BTreeSet::from([DEFAULT_EVENT.to_string()])),
+            on_event: RefCell::new(None),
+            on_opened: RefCell::new(None),
+            on_failed: RefCell::new(None),
+            last_event_id: RefCell::new(None),
+            reconnect: RefCell::new(Reconnect::default()),
+            codecs: RefCell::new(Codecs::new(Rc::new(Json))),
+        };
+        Self { inner: Rc::new(inner) }
+    }
+
+    /// Send cookies to another origin, applied from the next `connect`.
+    pub fn set_with_credentials(&mut self, with_credentials: bool) {
+        self.inner.with_credentials.set(with_credentials);
+    }
+
+    /// Also receive the events of type `event`.
+    pub fn add_event(&mut self, event: &str) {
+        if !self.inner.events.borrow_mut().insert(event.to_string()) {
+            return;
+        }
+        let source = self.inner.source.borrow();
+        let on_event = self.inner.on_event.borrow();
+        if let (Some(source), Some(on_event)) = (source.as_ref(), on_event.as_ref()) {
+            let _ = source.add_event_listener_with_callback(event, on_event.as_ref().unchecked_ref());
+        }
+    }
+
+    pub fn remove_event(&mut self, event: &str) {
+        if !self.inner.events.borrow_mut().remove(event) {
+            return;
+        }
+        let source = self.inner.source.borrow();
+        let on_event = self.inner.on_event.borrow();
+        if let (Some(source), Some(on_event)) = (source.as_ref(), on_event.as_ref()) {
+            let _ = source.remove_event_listener_with_callback(event, on_event.as_ref().unchecked_ref());
+        }
+    }
+
+    /// Event types received.
+    pub fn events(&self) -> Vec<String> {
+        self.inner.events.borrow().iter().cloned().collect()
+    }
+
+    /// Reconnect with this policy when the connection fails, resuming after the last event received.
+    /// None leaves it to the browser, which gives up once the server refuses the connection.
+    pub fn set_reconnect_policy(&mut self, policy: Option<ReconnectPolicy>) {
+        self.inner.reconnect.borrow_mut().policy = policy;
+    }
+
+    /// Notified on every change of `state`.
+    pub fn set_on_state(&mut self, on_state: Callback<ConnectionState>) {
+        self.inner.reconnect.borrow_mut().on_state = on_state;
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.inner.reconnect.borrow().state
+    }
+
+    /// Codec of the events without their own, JSON by default.
+    pub fn set_codec(&mut self, codec: Rc<dyn Codec>) {
+        self.inner.codecs.borrow_mut().set_default(codec);
+    }
+
+    /// Codec of the events of type `event`, None falls back to the source's.
+    pub fn set_event_codec(&mut self, event: &str, codec: Option<Rc<dyn Codec>>) {
+        let mut codecs = self.inner.codecs.borrow_mut();
+        match codec {
+            Some(codec) => codecs.set_topic(event, codec),
+            None => codecs.remove_topic(event),
+        };
+    }
+
+    /// Decode the data of an event with the codec of its type.
+    pub fn decode<T: DeserializeOwned>(&self, event: &str, data: &[u8]) -> Result<T, CodecError> {
+        let codec = self.inner.codecs.borrow().get(event);
+        codec::decode(codec.as_ref(), data)
+    }
+
+    /// Id of the last event received with one, sent when reconnecting.
+    pub fn last_event_id(&self) -> Option<String> {
+        self.inner.last_event_id.borrow().clone()
+    }
+
+    /// Start after the event `id` on the next connection, None to start from the current events.
+    pub fn set_last_event_id(&mut self, id: Option<String>) {
+        *self.inner.last_event_id.borrow_mut() = id;
+    }
+
+    pub fn connect(&mut self) -> bool {
+        {
+            let mut r = self.inner.reconnect.borrow_mut();
+            r.epoch = r.epoch.wrapping_add(1);
+            r.attempt = 0;
+        }
+        open_source(&self.inner)
+    }
+
+    pub fn close(&mut self) {
+        {
+            let mut r = self.inner.reconnect.borrow_mut();
+            r.epoch = r.epoch.wrapping_add(1);
+        }
+        if let Some(source) = self.inner.source.take() {
+            source.close();
+        }
+        set_state(&self.inner, ConnectionState::Closed);
+    }
+
+    pub fn is_connected(&self) -> bool {
+        matches!(self.inner.source.borrow().as_ref(), Some(s) if s.ready_state() == EventSource::OPEN)
+    }
+}
+
+impl Drop for MyEventSource {
+    fn drop(&mut self) {
+        if let Some(source) = self.inner.source.take() {
+            source.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resume_url() {
+        assert_eq!(resume_url("/api/v1/events", None), "/api/v1/events");
+        assert_eq!(resume_url("/api/v1/events", Some("42")), "/api/v1/events?lastEventId=42");
+        assert_eq!(
+            resume_url("/api/v1/events?plant=1&lastEventId=7#log", Some("a b")),
+            "/api/v1/events?plant=1&lastEventId=a%20b#log"
+        );
+    }
+}
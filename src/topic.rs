@@ -0,0 +1,290 @@
+//! MQTT topic names and topic filters.
+
+use std::collections::HashMap;
+
+/// Whether `filter` is a valid topic filter: `#` only as the last level, wildcards fill a whole level.
+pub fn valid_filter(filter: &str) -> bool {
+    if filter.is_empty() {
+        return false;
+    }
+    let mut levels = filter.split('/').peekable();
+    while let Some(level) = levels.next() {
+        match level {
+            "#" if levels.peek().is_some() => return false,
+            "#" | "+" => {}
+            _ if level.contains(['#', '+']) => return false,
+            _ => {}
+        }
+    }
+    true
+}
+
+/// Whether `topic` is a valid topic name to publish to, without wildcards.
+pub fn valid_topic(topic: &str) -> bool {
+    !topic.is_empty() && !topic.contains(['#', '+'])
+}
+
+/// Whether the topic name `topic` matches `filter`, following MQTT:
+/// `+` matches exactly one level, `#` the parent level and any number of child levels,
+/// and topics starting with `$` are not matched by filters starting with a wildcard.
+pub fn matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && filter.starts_with(['+', '#']) {
+        return false;
+    }
+    let mut filter = filter.split('/');
+    let mut topic = topic.split('/');
+    loop {
+        match (filter.next(), topic.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Handlers registered with topic filters, found by topic name through a trie of filter levels.
+pub struct TopicRouter<H> {
+    root: Node<H>,
+    /// Filter of every handler id
+    filters: HashMap<usize, String>,
+    next_id: usize,
+}
+
+struct Node<H> {
+    children: HashMap<String, Node<H>>,
+    handlers: Vec<(usize, H)>,
+}
+
+impl<H> Default for Node<H> {
+    fn default() -> Self {
+        Self {
+            children: HashMap::new(),
+            handlers: Vec::new(),
+        }
+    }
+}
+
+impl<H> Node<H> {
+    fn is_empty(&self) -> bool {
+        self.children.is_empty() && self.handlers.is_empty()
+    }
+
+    fn collect<'a>(&'a self, levels: &[&str], out: &mut Vec<&'a H>) {
+        let Some((level, rest)) = levels.split_first() else {
+            out.extend(self.handlers.iter().map(|(_, h)| h));
+            // `#` also matches the parent level
+            if let Some(node) = self.children.get("#") {
+                out.extend(node.handlers.iter().map(|(_, h)| h));
+            }
+            return;
+        };
+        if let Some(node) = self.children.get(*level) {
+            node.collect(rest, out);
+        }
+        if let Some(node) = self.children.get("+") {
+            node.collect(rest, out);
+        }
+        if let Some(node) = self.children.get("#") {
+            out.extend(node.handlers.iter().map(|(_, h)| h));
+        }
+    }
+
+    /// Returns the handler, prunes nodes left empty.
+    fn remove(&mut self, levels: &[&str], id: usize) -> Option<H> {
+        match levels.split_first() {
+            None => {
+                let index = self.handlers.iter().position(|(i, _)| *i == id)?;
+                Some(self.handlers.remove(index).1)
+            }
+            Some((level, rest)) => {
+                let node = self.children.get_mut(*level)?;
+                let handler = node.remove(rest, id);
+                if node.is_empty() {
+                    self.children.remove(*level);
+                }
+                handler
+            }
+        }
+    }
+}
+
+impl<H> Default for TopicRouter<H> {
+    fn default() -> Self {
+        Self {
+            root: Node::default(),
+            filters: HashMap::new(),
+            next_id: 0,
+        }
+    }
+}
+
+impl<H> TopicRouter<H> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the id of the handler, None if `filter` is not a valid topic filter.
+    pub fn insert(&mut self, filter: &str, handler: H) -> Option<usize> {
+        if !valid_filter(filter) {
+            return None;
+        }
+        let mut node = &mut self.root;
+        for level in filter.split('/') {
+            node = node.children.entry(level.to_string()).or_default();
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        node.handlers.push((id, handler));
+        self.filters.insert(id, filter.to_string());
+        Some(id)
+    }
+
+    /// Returns the filter and the handler.
+    pub fn remove(&mut self, id: usize) -> Option<(String, H)> {
+        let filter = self.filters.remove(&id)?;
+        let levels: Vec<_> = filter.split('/').collect();
+        let handler = self.root.remove(&levels, id)?;
+        Some((filter, handler))
+    }
+
+    /// Whether some handler is registered with exactly `filter`.
+    pub fn contains_filter(&self, filter: &str) -> bool {
+        self.filters.values().any(|f| f == filter)
+    }
+
+    /// Distinct filters of the registered handlers.
+    pub fn filters(&self) -> Vec<String> {
+        let mut filters: Vec<_> = self.filters.values().cloned().collect();
+        filters.sort();
+        filters.dedup();
+        filters
+    }
+
+    pub fn len(&self) -> usize {
+        self.filters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// Handlers whose filter matches the topic name `topic`, see `matches`.
+    pub fn route(&self, topic: &str) -> Vec<&H> {
+        let mut out = Vec::new();
+        if topic.is_empty() {
+            return out;
+        }
+        let levels: Vec<_> = topic.split('/').collect();
+        if topic.starts_with('$') {
+            // wildcards can not match the first level of system topics
+            if let Some(node) = self.root.children.get(levels[0]) {
+                node.collect(&levels[1..], &mut out);
+            }
+        } else {
+            self.root.collect(&levels, &mut out);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("plant/1/measure", "plant/1/measure"));
+        assert!(!matches("plant/1/measure", "plant/2/measure"));
+        assert!(matches("plant/+/measure", "plant/2/measure"));
+        assert!(!matches("plant/+", "plant/2/measure"));
+        assert!(matches("plant/#", "plant/2/measure"));
+        assert!(matches("plant/#", "plant"));
+        assert!(matches("#", "plant/2"));
+        assert!(!matches("#", "$SYS/uptime"));
+        assert!(matches("$SYS/#", "$SYS/uptime"));
+        assert!(valid_filter("plant/+/#"));
+        assert!(!valid_filter("plant/#/measure"));
+        assert!(!valid_filter("plant+/1"));
+        assert!(!valid_topic("plant/+"));
+    }
+
+    /// Topics matched by `filter` through the router, checked against `matches`.
+    fn routed(filter: &str, topics: &[&str]) -> Vec<String> {
+        let mut router = TopicRouter::new();
+        router.insert(filter, ()).unwrap();
+        topics
+            .iter()
+            .filter(|topic| {
+                let routed = !router.route(topic).is_empty();
+                assert_eq!(routed, matches(filter, topic), "{} {}", filter, topic);
+                routed
+            })
+            .map(|topic| topic.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_spec_examples() {
+        let topics = [
+            "sport",
+            "sport/",
+            "sport/tennis",
+            "sport/tennis/player1",
+            "sport/tennis/player1/ranking",
+            "sport/tennis/player1/score/wimbledon",
+            "sport/tennis/player2",
+            "/finance",
+            "finance",
+            "$SYS/monitor/Clients",
+            "$SYS",
+        ];
+        assert_eq!(
+            routed("sport/tennis/player1/#", &topics),
+            vec![
+                "sport/tennis/player1",
+                "sport/tennis/player1/ranking",
+                "sport/tennis/player1/score/wimbledon"
+            ]
+        );
+        assert_eq!(routed("sport/#", &topics).len(), 7);
+        assert_eq!(routed("sport/tennis/+", &topics), vec!["sport/tennis/player1", "sport/tennis/player2"]);
+        assert_eq!(routed("sport/+", &topics), vec!["sport/", "sport/tennis"]);
+        assert_eq!(routed("+", &topics), vec!["sport", "finance"]);
+        assert_eq!(routed("+/+", &topics), vec!["sport/", "sport/tennis", "/finance"]);
+        assert_eq!(routed("/+", &topics), vec!["/finance"]);
+        assert_eq!(routed("#", &topics).len(), 9);
+        assert!(routed("+/monitor/Clients", &topics).is_empty());
+        assert_eq!(routed("$SYS/#", &topics), vec!["$SYS/monitor/Clients", "$SYS"]);
+        assert_eq!(routed("$SYS/monitor/+", &topics), vec!["$SYS/monitor/Clients"]);
+        assert_eq!(routed("sport//player1", &["sport//player1", "sport/tennis/player1"]), vec!["sport//player1"]);
+    }
+
+    #[test]
+    fn test_router() {
+        let mut router = TopicRouter::new();
+        let exact = router.insert("plant/1/measure", "exact").unwrap();
+        let plus = router.insert("plant/+/measure", "plus").unwrap();
+        router.insert("plant/#", "hash").unwrap();
+        let again = router.insert("plant/+/measure", "plus again").unwrap();
+        assert_eq!(router.insert("plant/#/measure", "invalid"), None);
+        assert_eq!(router.len(), 4);
+
+        let mut routed = router.route("plant/1/measure");
+        routed.sort();
+        assert_eq!(routed, vec![&"exact", &"hash", &"plus", &"plus again"]);
+        assert_eq!(router.route("plant/2/measure").len(), 3);
+        assert_eq!(router.route("plant"), vec![&"hash"]);
+        assert!(router.route("other/1/measure").is_empty());
+
+        assert_eq!(router.remove(plus), Some(("plant/+/measure".to_string(), "plus")));
+        assert_eq!(router.remove(plus), None);
+        assert!(router.contains_filter("plant/+/measure"));
+        router.remove(again);
+        assert!(!router.contains_filter("plant/+/measure"));
+        router.remove(exact);
+        assert_eq!(router.filters(), vec!["plant/#".to_string()]);
+        assert_eq!(router.route("plant/1/measure"), vec![&"hash"]);
+    }
+}
@@ -0,0 +1,430 @@
+//! Uploads of files larger than one request may carry, sent in chunks that the server
+//! confirms one by one, so that a failed upload resumes after the last confirmed chunk.
+//!
+//! The file is identified by its SHA-256 hash, which the server checks once every chunk
+//! is received. See `HttpChunkServer` for the requests sent.
+
+use std::cell::Cell;
+use std::future::Future;
+use std::ops::Range;
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::Headers;
+use yew::platform::time::sleep;
+use yew::Callback;
+
+use super::UploadProgress;
+use crate::fetch::{AbortHandle, FetchError, Method, RequestBuilder, RetryPolicy};
+
+/// A file split into chunks of `chunk_size` bytes, the last one may be shorter.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkInfo {
+    pub name: String,
+    pub size: u64,
+    pub chunk_size: u64,
+    pub chunks: u32,
+    /// SHA-256 of the whole file, as lowercase hex
+    pub hash: String,
+}
+
+impl ChunkInfo {
+    /// Read `source` once to hash it.
+    pub async fn read<S: ChunkSource + ?Sized>(name: &str, source: &S, chunk_size: u64) -> Result<Self, FetchError> {
+        let chunk_size = chunk_size.max(1);
+        let size = source.size();
+        let chunks = size.div_ceil(chunk_size) as u32;
+        let mut info = ChunkInfo {
+            name: name.to_string(),
+            size,
+            chunk_size,
+            chunks,
+            hash: String::new(),
+        };
+        let mut hasher = Sha256::new();
+        for index in 0..chunks {
+            hasher.update(&source.read(info.range(index)).await?);
+        }
+        info.hash = to_hex(&hasher.finalize());
+        Ok(info)
+    }
+
+    /// Bytes of the chunk `index`.
+    pub fn range(&self, index: u32) -> Range<u64> {
+        let start = (index as u64 * self.chunk_size).min(self.size);
+        start..(start + self.chunk_size).min(self.size)
+    }
+
+    /// Bytes in the first `chunks` chunks.
+    pub fn sent(&self, chunks: u32) -> u64 {
+        (chunks as u64 * self.chunk_size).min(self.size)
+    }
+}
+
+/// Lowercase hex of a digest.
+fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The content to upload.
+pub trait ChunkSource {
+    fn size(&self) -> u64;
+    fn read(&self, range: Range<u64>) -> impl Future<Output = Result<Vec<u8>, FetchError>>;
+}
+
+impl ChunkSource for web_sys::File {
+    fn size(&self) -> u64 {
+        web_sys::Blob::size(self) as u64
+    }
+
+    async fn read(&self, range: Range<u64>) -> Result<Vec<u8>, FetchError> {
+        let blob = self.slice_with_f64_and_f64(range.start as f64, range.end as f64)?;
+        let buf = JsFuture::from(blob.array_buffer()).await?;
+        Ok(js_sys::Uint8Array::new(&buf).to_vec())
+    }
+}
+
+impl ChunkSource for [u8] {
+    fn size(&self) -> u64 {
+        self.len() as u64
+    }
+
+    async fn read(&self, range: Range<u64>) -> Result<Vec<u8>, FetchError> {
+        Ok(self[range.start as usize..range.end as usize].to_vec())
+    }
+}
+
+/// The server receiving the chunks.
+pub trait ChunkServer {
+    /// Number of chunks already stored, from the first one. The upload goes on from there.
+    fn confirmed(&self, info: &ChunkInfo) -> impl Future<Output = Result<u32, FetchError>>;
+    /// Store the chunk `index`, sending it again replaces it.
+    fn send(&self, info: &ChunkInfo, index: u32, data: Vec<u8>) -> impl Future<Output = Result<(), FetchError>>;
+    /// Join the chunks and check the hash, returns the response body.
+    fn complete(&self, info: &ChunkInfo) -> impl Future<Output = Result<Vec<u8>, FetchError>>;
+}
+
+/// Chunks sent to one url, with the query parameters `hash` and `index` where needed:
+/// - `GET` with `hash`, `size` and `chunk_size` answers the number of confirmed chunks
+/// - `PUT` with `hash` and `index` has the chunk as body
+/// - `POST` has the `ChunkInfo` as JSON body, answers an error status if the hash does not match
+#[derive(Clone)]
+pub struct HttpChunkServer {
+    url: String,
+    headers: Option<Headers>,
+    abort: Option<AbortHandle>,
+}
+
+impl HttpChunkServer {
+    pub fn new<S: Into<String>>(url: S) -> Self {
+        Self {
+            url: url.into(),
+            headers: None,
+            abort: None,
+        }
+    }
+
+    pub fn headers(mut self, headers: &Headers) -> Self {
+        self.headers = Some(headers.clone());
+        self
+    }
+
+    pub fn abort_handle(mut self, handle: &AbortHandle) -> Self {
+        self.abort = Some(handle.clone());
+        self
+    }
+
+    fn request(&self, method: Method, info: &ChunkInfo) -> RequestBuilder {
+        let mut builder = RequestBuilder::new(method, self.url.as_str()).query("hash", &info.hash);
+        if let Some(headers) = &self.headers {
+            builder = builder.headers(headers);
+        }
+        if let Some(handle) = &self.abort {
+            builder = builder.abort_handle(handle);
+        }
+        builder
+    }
+}
+
+impl ChunkServer for HttpChunkServer {
+    async fn confirmed(&self, info: &ChunkInfo) -> Result<u32, FetchError> {
+        self.request(Method::Get, info)
+            .query("size", info.size)
+            .query("chunk_size", info.chunk_size)
+            .decode()
+            .await
+    }
+
+    async fn send(&self, info: &ChunkInfo, index: u32, data: Vec<u8>) -> Result<(), FetchError> {
+        self.request(Method::Put, info)
+            .query("index", index)
+            .header("Content-Type", "application/octet-stream")
+            .raw(data)
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn complete(&self, info: &ChunkInfo) -> Result<Vec<u8>, FetchError> {
+        self.request(Method::Post, info).json(info).bytes().await
+    }
+}
+
+/// Sends a `ChunkSource` to a `ChunkServer`. After a failure allowed by the retry policy,
+/// asks the server which chunks it has and sends the rest. The attempts are counted again
+/// once a chunk gets through.
+///
+/// ```ignore
+/// let server = HttpChunkServer::new("/api/v1/archives").headers(&header);
+/// let body = ChunkedUpload::new(8 << 20).upload(&file.name(), &file, &server).await?;
+/// ```
+#[derive(Clone)]
+pub struct ChunkedUpload {
+    chunk_size: u64,
+    retry: RetryPolicy,
+    on_progress: Callback<UploadProgress>,
+    abort: Option<AbortHandle>,
+}
+
+impl ChunkedUpload {
+    pub fn new(chunk_size: u64) -> Self {
+        let mut retry = RetryPolicy::default();
+        retry.set_max_attempts(5);
+        Self {
+            chunk_size,
+            retry,
+            on_progress: Callback::noop(),
+            abort: None,
+        }
+    }
+
+    /// Five attempts by default, the method is always `PUT`.
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Bytes confirmed by the server, reported after each chunk.
+    pub fn on_progress(mut self, on_progress: Callback<UploadProgress>) -> Self {
+        self.on_progress = on_progress;
+        self
+    }
+
+    /// Checked before each request, the server has to be given
+    /// the same handle to stop the request in flight.
+    pub fn abort_handle(mut self, handle: &AbortHandle) -> Self {
+        self.abort = Some(handle.clone());
+        self
+    }
+
+    pub async fn upload<S, T>(&self, name: &str, source: &S, server: &T) -> Result<Vec<u8>, FetchError>
+    where
+        S: ChunkSource + ?Sized,
+        T: ChunkServer,
+    {
+        let aborted = Rc::new(Cell::new(false));
+        self.arm(&aborted)?;
+        let info = ChunkInfo::read(name, source, self.chunk_size).await?;
+        let mut confirmed = 0;
+        let mut attempt = 1;
+        loop {
+            let before = confirmed;
+            let e = match self.resume(&info, source, server, &mut confirmed, &aborted).await {
+                Ok(body) => return Ok(body),
+                Err(e) => e,
+            };
+            if confirmed > before {
+                attempt = 1;
+            }
+            if !self.retry.should_retry(Method::Put, attempt, &e) {
+                return Err(e);
+            }
+            let delay = self.retry.delay(attempt);
+            log::debug!("Resume upload of {} in {:?} after: {}", info.name, delay, e);
+            if !delay.is_zero() {
+                sleep(delay).await;
+            }
+            attempt += 1;
+        }
+    }
+
+    async fn resume<S, T>(
+        &self,
+        info: &ChunkInfo,
+        source: &S,
+        server: &T,
+        confirmed: &mut u32,
+        aborted: &Rc<Cell<bool>>,
+    ) -> Result<Vec<u8>, FetchError>
+    where
+        S: ChunkSource + ?Sized,
+        T: ChunkServer,
+    {
+        self.arm(aborted)?;
+        *confirmed = server.confirmed(info).await?.min(info.chunks);
+        self.report(info, *confirmed);
+        while *confirmed < info.chunks {
+            self.arm(aborted)?;
+            let data = source.read(info.range(*confirmed)).await?;
+            server.send(info, *confirmed, data).await?;
+            *confirmed += 1;
+            self.report(info, *confirmed);
+        }
+        self.arm(aborted)?;
+        server.complete(info).await
+    }
+
+    /// Fails if the handle was used since the last call, then watches it again
+    /// until a request of the server takes it over.
+    fn arm(&self, aborted: &Rc<Cell<bool>>) -> Result<(), FetchError> {
+        if aborted.get() {
+            return Err(FetchError::Aborted);
+        }
+        if let Some(handle) = &self.abort {
+            let aborted = aborted.clone();
+            handle.set(move || aborted.set(true));
+        }
+        Ok(())
+    }
+
+    fn report(&self, info: &ChunkInfo, confirmed: u32) {
+        self.on_progress.emit(UploadProgress {
+            loaded: info.sent(confirmed),
+            total: info.size,
+        });
+    }
+}
+
+/// `async_ws_post_file` for files larger than one request, see `HttpChunkServer` for the protocol.
+pub async fn async_ws_post_file_chunked(
+    url: &str,
+    header: &Headers,
+    file: &web_sys::File,
+    chunk_size: u64,
+    on_progress: Callback<UploadProgress>,
+    abort: &AbortHandle,
+) -> Result<Vec<u8>, FetchError> {
+    let server = HttpChunkServer::new(url).headers(header).abort_handle(abort);
+    ChunkedUpload::new(chunk_size)
+        .on_progress(on_progress)
+        .abort_handle(abort)
+        .upload(&file.name(), file, &server)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use futures::FutureExt;
+
+    use super::*;
+
+    /// Keeps the chunks in memory, the chunk `fail_at` fails to arrive once.
+    #[derive(Default)]
+    struct StandIn {
+        files: RefCell<HashMap<String, Vec<Option<Vec<u8>>>>>,
+        fail_at: Cell<Option<u32>>,
+        sent: RefCell<Vec<u32>>,
+    }
+
+    impl ChunkServer for StandIn {
+        async fn confirmed(&self, info: &ChunkInfo) -> Result<u32, FetchError> {
+            let files = self.files.borrow();
+            let chunks = files.get(&info.hash).map(|c| c.iter().take_while(|c| c.is_some()).count());
+            Ok(chunks.unwrap_or(0) as u32)
+        }
+
+        async fn send(&self, info: &ChunkInfo, index: u32, data: Vec<u8>) -> Result<(), FetchError> {
+            if self.fail_at.get() == Some(index) {
+                self.fail_at.set(None);
+                return Err(FetchError::Network("connection reset".to_string()));
+            }
+            self.sent.borrow_mut().push(index);
+            let mut files = self.files.borrow_mut();
+            let chunks = files.entry(info.hash.clone()).or_insert_with(|| vec![None; info.chunks as usize]);
+            chunks[index as usize] = Some(data);
+            Ok(())
+        }
+
+        async fn complete(&self, info: &ChunkInfo) -> Result<Vec<u8>, FetchError> {
+            let files = self.files.borrow();
+            let file: Vec<u8> = files[&info.hash].iter().flat_map(|c| c.clone().unwrap()).collect();
+            if file.len() as u64 != info.size || to_hex(&Sha256::digest(&file)) != info.hash {
+                return Err(FetchError::HttpStatus { code: 400, body: "hash mismatch".to_string() });
+            }
+            Ok(file)
+        }
+    }
+
+    /// The stand-in answers at once, so the uploads never wait.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        future.now_or_never().unwrap()
+    }
+
+    fn no_delay(max_attempts: u32) -> RetryPolicy {
+        let mut policy = RetryPolicy::default();
+        policy.set_max_attempts(max_attempts).set_initial_delay(Duration::ZERO);
+        policy
+    }
+
+    #[test]
+    fn test_chunk_info() {
+        let data: Vec<u8> = (0..2500u32).map(|i| i as u8).collect();
+        let info = block_on(ChunkInfo::read("a.bin", data.as_slice(), 1000)).unwrap();
+        assert_eq!(info.chunks, 3);
+        assert_eq!(info.range(2), 2000..2500);
+        assert_eq!(info.sent(2), 2000);
+        assert_eq!(info.sent(3), 2500);
+        assert_eq!(info.hash, to_hex(&Sha256::digest(&data)));
+        let abc = block_on(ChunkInfo::read("abc", b"abc".as_slice(), 2)).unwrap();
+        assert_eq!(abc.hash, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        let empty = block_on(ChunkInfo::read("empty", [].as_slice(), 1000)).unwrap();
+        assert_eq!(empty.chunks, 0);
+    }
+
+    #[test]
+    fn test_resume() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7) as u8).collect();
+        let server = StandIn::default();
+        server.fail_at.set(Some(4));
+        let progress = Rc::new(RefCell::new(Vec::new()));
+        let p = progress.clone();
+        let upload = ChunkedUpload::new(1000)
+            .retry(no_delay(3))
+            .on_progress(Callback::from(move |e: UploadProgress| p.borrow_mut().push(e.loaded)));
+        let body = block_on(upload.upload("a.bin", data.as_slice(), &server)).unwrap();
+        assert_eq!(body, data);
+        // the chunks confirmed before the failure are not sent again
+        assert_eq!(*server.sent.borrow(), (0..10).collect::<Vec<_>>());
+        assert_eq!(progress.borrow().last(), Some(&10_000));
+        assert!(progress.borrow().contains(&4000));
+
+        // a new upload of the same file goes on from what the server has
+        let server = StandIn::default();
+        server.fail_at.set(Some(6));
+        let upload = ChunkedUpload::new(1000).retry(no_delay(1));
+        let e = block_on(upload.upload("a.bin", data.as_slice(), &server)).unwrap_err();
+        assert_eq!(e, FetchError::Network("connection reset".to_string()));
+        server.sent.borrow_mut().clear();
+        assert_eq!(block_on(upload.upload("a.bin", data.as_slice(), &server)).unwrap(), data);
+        assert_eq!(*server.sent.borrow(), (6..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_verify() {
+        let data = vec![1u8; 3000];
+        let server = StandIn::default();
+        let upload = ChunkedUpload::new(1000).retry(no_delay(1));
+        block_on(upload.upload("a.bin", data.as_slice(), &server)).unwrap();
+        // a chunk corrupted on the server is caught on completion
+        let hash = server.files.borrow().keys().next().unwrap().clone();
+        server.files.borrow_mut().get_mut(&hash).unwrap()[1] = Some(vec![0; 1000]);
+        let e = block_on(upload.upload("a.bin", data.as_slice(), &server)).unwrap_err();
+        assert!(matches!(e, FetchError::HttpStatus { code: 400, .. }));
+    }
+}
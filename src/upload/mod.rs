@@ -0,0 +1,164 @@
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{Headers, ProgressEvent, XmlHttpRequest, XmlHttpRequestResponseType};
+use yew::Callback;
+
+use crate::cache::invalidate_cache;
+use crate::fetch::{header_error, AbortHandle, FetchError, RequestBuilder};
+
+pub use chunked::*;
+
+mod chunked;
+
+/// Bytes of a request body sent so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UploadProgress {
+    pub loaded: u64,
+    /// Zero if unknown
+    pub total: u64,
+}
+
+impl UploadProgress {
+    /// Between 0 and 1.
+    pub fn fraction(&self) -> f32 {
+        if self.total == 0 {
+            0.0
+        } else {
+            (self.loaded as f64 / self.total as f64).min(1.0) as f32
+        }
+    }
+
+    /// Progress of each file of a multipart body, from their sizes in sending order.
+    /// The bytes of the other parts are spread over the files.
+    pub fn per_file(&self, sizes: &[u64]) -> Vec<f32> {
+        let sum: u64 = sizes.iter().sum();
+        let sent = (self.fraction() as f64 * sum as f64).round() as u64;
+        let mut start = 0;
+        sizes
+            .iter()
+            .map(|size| {
+                let loaded = sent.saturating_sub(start).min(*size);
+                start += size;
+                if *size == 0 {
+                    if sent >= start { 1.0 } else { 0.0 }
+                } else {
+                    loaded as f32 / *size as f32
+                }
+            })
+            .collect()
+    }
+}
+
+impl RequestBuilder {
+    /// Send with `XMLHttpRequest` instead of `fetch` to report the progress of the body,
+    /// e.g. files added with `file` and `field`. Returns the response body.
+    ///
+    /// The timeout, `AbortHandle` and the `ClientConfig` apply, the retry policy,
+    /// `cancel_previous` and the token refresh do not.
+    pub async fn upload(self, on_progress: Callback<UploadProgress>) -> Result<Vec<u8>, FetchError> {
+        let request = self.prepare()?;
+        let xhr = XmlHttpRequest::new()?;
+        xhr.open_with_async(&request.method.to_string(), &request.url, true)?;
+        for (k, v) in &request.headers {
+            xhr.set_request_header(k, v)?;
+        }
+        xhr.set_response_type(XmlHttpRequestResponseType::Arraybuffer);
+        if let Some(timeout) = request.timeout {
+            xhr.set_timeout(timeout.as_millis().min(u32::MAX as u128) as u32);
+        }
+        let on_progress = Closure::<dyn Fn(ProgressEvent)>::new(move |e: ProgressEvent| {
+            let total = if e.length_computable() { e.total() as u64 } else { 0 };
+            on_progress.emit(UploadProgress {
+                loaded: e.loaded() as u64,
+                total,
+            });
+        });
+        xhr.upload()?.set_onprogress(Some(on_progress.as_ref().unchecked_ref()));
+        if let Some(handle) = &request.abort {
+            let xhr = xhr.clone();
+            handle.set(move || {
+                let _ = xhr.abort();
+            });
+        }
+        let promise = js_sys::Promise::new(&mut |resolve, reject| {
+            xhr.set_onload(Some(&resolve));
+            xhr.set_onerror(Some(&reject));
+            xhr.set_onabort(Some(&reject));
+            xhr.set_ontimeout(Some(&reject));
+        });
+        match &request.body {
+            Some(body) if body.is_string() => xhr.send_with_opt_str(body.as_string().as_deref())?,
+            // send accepts any body of fetch, the binding only narrows the type
+            Some(body) => xhr.send_with_opt_buffer_source(Some(body.unchecked_ref()))?,
+            None => xhr.send()?,
+        }
+        if let Err(e) = JsFuture::from(promise).await {
+            let kind = e.dyn_ref::<web_sys::Event>().map(|e| e.type_()).unwrap_or_default();
+            return Err(match kind.as_str() {
+                "abort" => FetchError::Aborted,
+                "timeout" => FetchError::Timeout,
+                _ => FetchError::Network(format!("failed to upload to {}", request.url)),
+            });
+        }
+        // keep the listener alive until the upload is over
+        drop(on_progress);
+        let body = response_body(&xhr)?;
+        invalidate_cache(&request.url);
+        Ok(body)
+    }
+}
+
+fn response_body(xhr: &XmlHttpRequest) -> Result<Vec<u8>, FetchError> {
+    let status = xhr.status()?;
+    if let Some(e) = header_error(|name| xhr.get_response_header(name).ok().flatten()) {
+        return Err(e);
+    }
+    let body = match xhr.response()?.dyn_into::<js_sys::ArrayBuffer>() {
+        Ok(buf) => js_sys::Uint8Array::new(&buf).to_vec(),
+        Err(_) => Vec::new(),
+    };
+    if status != 200 {
+        let body = String::from_utf8_lossy(&body).to_string();
+        return Err(FetchError::HttpStatus { code: status, body });
+    }
+    Ok(body)
+}
+
+/// `async_ws_post_file` for several files sent as one multipart request, with extra form
+/// fields, the progress of the upload and a handle to cancel it.
+pub async fn async_ws_post_files(
+    url: &str,
+    header: &Headers,
+    files: &[web_sys::File],
+    fields: &[(String, String)],
+    on_progress: Callback<UploadProgress>,
+    abort: &AbortHandle,
+) -> Result<Vec<u8>, FetchError> {
+    let mut builder = RequestBuilder::post(url).headers(header).abort_handle(abort);
+    for file in files {
+        builder = builder.file("file", file);
+    }
+    for (k, v) in fields {
+        builder = builder.field(k.clone(), v.clone());
+    }
+    builder.upload(on_progress).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress() {
+        let progress = UploadProgress { loaded: 150, total: 400 };
+        assert_eq!(progress.fraction(), 0.375);
+        assert_eq!(UploadProgress::default().fraction(), 0.0);
+        assert_eq!(progress.per_file(&[100, 100, 200]), vec![1.0, 0.5, 0.0]);
+        // the multipart boundaries make the total larger than the files
+        let half = UploadProgress { loaded: 200, total: 400 };
+        assert_eq!(half.per_file(&[100, 200]), vec![1.0, 0.25]);
+        let done = UploadProgress { loaded: 400, total: 400 };
+        assert_eq!(done.per_file(&[100, 0, 100]), vec![1.0, 1.0, 1.0]);
+    }
+}